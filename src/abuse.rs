@@ -0,0 +1,268 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::Mutex;
+
+// More messages than this to the bot within the window count as spam
+const SPAM_MESSAGES: usize = 5;
+const SPAM_WINDOW_SECONDS: i64 = 10;
+// This many strikes within the window get a user muted
+const STRIKES_TO_MUTE: usize = 3;
+const STRIKE_WINDOW_MINUTES: i64 = 10;
+// Every mute lasts twice as long as the previous one, up to a day.
+// Users who stay out of trouble for a day start over from the first duration.
+const FIRST_MUTE_MINUTES: i64 = 5;
+const MAX_MUTE_HOURS: i64 = 24;
+const MUTE_MEMORY_HOURS: i64 = 24;
+
+pub struct Mute {
+    pub duration: Duration,
+    pub until: DateTime<Utc>,
+    // How many times in a row the user was muted, this one included
+    pub count: u32,
+}
+
+#[derive(Default)]
+struct UserRecord {
+    messages: VecDeque<DateTime<Utc>>,
+    strikes: Vec<DateTime<Utc>>,
+    mutes: u32,
+    muted_until: Option<DateTime<Utc>>,
+    // When the last mute ended or will end, an early unmute included, for forgiving users who stay out of trouble
+    last_mute_end: Option<DateTime<Utc>>,
+}
+
+impl UserRecord {
+    // Nothing left that could affect the user's next message, the record can be dropped
+    fn idle(&self, now: DateTime<Utc>) -> bool {
+        self.messages
+            .back()
+            .is_none_or(|sent| now - *sent > Duration::seconds(SPAM_WINDOW_SECONDS))
+            && self
+                .strikes
+                .last()
+                .is_none_or(|struck| now - *struck > Duration::minutes(STRIKE_WINDOW_MINUTES))
+            && self
+                .last_mute_end
+                .is_none_or(|end| now - end > Duration::hours(MUTE_MEMORY_HOURS))
+    }
+}
+
+// Strikes and temporary mutes for users misbehaving in a guild, kept in memory only
+#[derive(Default)]
+pub struct AbuseTracker {
+    users: Mutex<HashMap<(u64, u64), UserRecord>>,
+}
+
+impl AbuseTracker {
+    pub async fn muted_until(&self, guild_id: u64, user_id: u64) -> Option<DateTime<Utc>> {
+        let users = self.users.lock().await;
+        users
+            .get(&(guild_id, user_id))
+            .and_then(|record| record.muted_until)
+            .filter(|until| *until > Utc::now())
+    }
+
+    // Counts a message sent to the bot, spam earns a strike
+    pub async fn record_message(&self, guild_id: u64, user_id: u64) -> (bool, Option<Mute>) {
+        self.record_message_at(guild_id, user_id, Utc::now()).await
+    }
+
+    pub async fn strike(&self, guild_id: u64, user_id: u64) -> Option<Mute> {
+        let mut users = self.users.lock().await;
+        let record = users.entry((guild_id, user_id)).or_default();
+        strike(record, Utc::now())
+    }
+
+    // Lifts a mute early, the user keeps their mute count so a next one is still longer
+    pub async fn unmute(&self, guild_id: u64, user_id: u64) -> bool {
+        self.unmute_at(guild_id, user_id, Utc::now()).await
+    }
+
+    async fn unmute_at(&self, guild_id: u64, user_id: u64, now: DateTime<Utc>) -> bool {
+        let mut users = self.users.lock().await;
+        match users.get_mut(&(guild_id, user_id)) {
+            Some(record) if record.muted_until.is_some_and(|until| until > now) => {
+                record.muted_until = None;
+                record.last_mute_end = Some(now);
+                record.strikes.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn record_message_at(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        now: DateTime<Utc>,
+    ) -> (bool, Option<Mute>) {
+        let mut users = self.users.lock().await;
+        // Every user who ever talked to the bot would be kept otherwise, new ones are a good time to let go of idle ones
+        if !users.contains_key(&(guild_id, user_id)) {
+            users.retain(|_, record| !record.idle(now));
+        }
+        let record = users.entry((guild_id, user_id)).or_default();
+
+        record.messages.push_back(now);
+        while record
+            .messages
+            .front()
+            .is_some_and(|sent| now - *sent > Duration::seconds(SPAM_WINDOW_SECONDS))
+        {
+            record.messages.pop_front();
+        }
+
+        if record.messages.len() <= SPAM_MESSAGES {
+            return (false, None);
+        }
+        // The burst counts as a single strike
+        record.messages.clear();
+        (true, strike(record, now))
+    }
+}
+
+fn strike(record: &mut UserRecord, now: DateTime<Utc>) -> Option<Mute> {
+    record
+        .strikes
+        .retain(|struck| now - *struck <= Duration::minutes(STRIKE_WINDOW_MINUTES));
+    record.strikes.push(now);
+    if record.strikes.len() < STRIKES_TO_MUTE {
+        return None;
+    }
+    record.strikes.clear();
+
+    let forgiven = record
+        .last_mute_end
+        .is_some_and(|end| now - end > Duration::hours(MUTE_MEMORY_HOURS));
+    if forgiven {
+        record.mutes = 0;
+    }
+
+    let duration = std::cmp::min(
+        Duration::minutes(FIRST_MUTE_MINUTES * 2i64.pow(record.mutes.min(16))),
+        Duration::hours(MAX_MUTE_HOURS),
+    );
+    record.mutes += 1;
+    let until = now + duration;
+    record.muted_until = Some(until);
+    record.last_mute_end = Some(until);
+
+    Some(Mute {
+        duration,
+        until,
+        count: record.mutes,
+    })
+}
+
+// "5 minutes", "2 hours", for messages about mutes
+pub fn describe_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    match minutes {
+        _ if minutes >= 60 && minutes % 60 == 0 => match minutes / 60 {
+            1 => "1 hour".to_string(),
+            hours => format!("{} hours", hours),
+        },
+        1 => "1 minute".to_string(),
+        _ => format!("{} minutes", minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spam_bursts_lead_to_escalating_mutes() {
+        let tracker = AbuseTracker::default();
+        let start = Utc::now();
+        let mut mutes = Vec::new();
+
+        // Three bursts make a mute, the next three a longer one
+        for burst in 0..6 {
+            let burst_start = start + Duration::minutes(burst);
+            for message in 0..=SPAM_MESSAGES as i64 {
+                let (_, mute) = tracker
+                    .record_message_at(1, 2, burst_start + Duration::seconds(message))
+                    .await;
+                mutes.extend(mute);
+            }
+        }
+
+        let durations: Vec<String> = mutes
+            .iter()
+            .map(|mute| describe_duration(mute.duration))
+            .collect();
+        assert_eq!(durations, ["5 minutes", "10 minutes"]);
+        assert_eq!(mutes[1].count, 2);
+    }
+
+    #[tokio::test]
+    async fn slow_messages_are_not_spam() {
+        let tracker = AbuseTracker::default();
+        let start = Utc::now();
+        for message in 0..20 {
+            let (spam, _) = tracker
+                .record_message_at(1, 2, start + Duration::seconds(message * 3))
+                .await;
+            assert!(!spam);
+        }
+    }
+
+    // Sends a burst of spam starting at `start`, returning the mute it led to if any
+    async fn burst(tracker: &AbuseTracker, user_id: u64, start: DateTime<Utc>) -> Option<Mute> {
+        let mut mutes = Vec::new();
+        for message in 0..=SPAM_MESSAGES as i64 {
+            let (_, mute) = tracker
+                .record_message_at(1, user_id, start + Duration::seconds(message))
+                .await;
+            mutes.extend(mute);
+        }
+        mutes.pop()
+    }
+
+    // Three bursts a minute apart, enough for a mute
+    async fn mute(tracker: &AbuseTracker, user_id: u64, start: DateTime<Utc>) -> Mute {
+        for minute in 0..2 {
+            assert!(burst(tracker, user_id, start + Duration::minutes(minute))
+                .await
+                .is_none());
+        }
+        burst(tracker, user_id, start + Duration::minutes(2))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn users_are_forgiven_a_day_after_an_early_unmute() {
+        let tracker = AbuseTracker::default();
+        let start = Utc::now();
+        let first = mute(&tracker, 2, start).await;
+        assert!(tracker.unmute_at(1, 2, start + Duration::minutes(3)).await);
+
+        let later = start + Duration::hours(MUTE_MEMORY_HOURS) + Duration::minutes(10);
+        assert_eq!(mute(&tracker, 2, later).await.count, 1);
+
+        // Without a day in between the next mute is longer
+        let again = mute(&tracker, 2, later + Duration::minutes(30)).await;
+        assert_eq!(again.count, 2);
+        assert!(again.duration > first.duration);
+    }
+
+    #[tokio::test]
+    async fn idle_users_are_dropped() {
+        let tracker = AbuseTracker::default();
+        let start = Utc::now();
+        tracker.record_message_at(1, 2, start).await;
+        mute(&tracker, 3, start).await;
+
+        // A new user shows up once the first one is idle, while the muted one is still remembered
+        tracker
+            .record_message_at(1, 4, start + Duration::hours(1))
+            .await;
+        let users = tracker.users.lock().await;
+        assert!(!users.contains_key(&(1, 2)));
+        assert!(users.contains_key(&(1, 3)));
+        assert!(users.contains_key(&(1, 4)));
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Who may use the bot in a guild, and where. Admins are never restricted, so they can't lock themselves out.
+// Channel lists hold channel or category ids, a rule on a category applies to every channel in it.
+// Denials win over allowances, and an empty allow list allows everything.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AccessRules {
+    #[serde(default)]
+    pub allowed_channels: Vec<u64>,
+    #[serde(default)]
+    pub denied_channels: Vec<u64>,
+    #[serde(default)]
+    pub allowed_roles: Vec<u64>,
+    #[serde(default)]
+    pub denied_roles: Vec<u64>,
+    // Roles that may run a command, keyed by command name. Commands without an entry are open to everyone.
+    #[serde(default)]
+    pub commands: HashMap<String, Vec<u64>>,
+    // Roles that may use a preset, keyed by preset name. Presets without an entry are open to everyone.
+    #[serde(default)]
+    pub presets: HashMap<String, Vec<u64>>,
+}
+
+impl AccessRules {
+    // Whether the bot answers a member with these roles in this channel, or why not
+    pub fn check(
+        &self,
+        channel_id: u64,
+        category_id: Option<u64>,
+        roles: &[u64],
+    ) -> Result<(), &'static str> {
+        let channels = [Some(channel_id), category_id];
+        let channels = || channels.iter().flatten();
+
+        if channels().any(|id| self.denied_channels.contains(id)) {
+            return Err("the channel is denied");
+        }
+        if !self.allowed_channels.is_empty()
+            && !channels().any(|id| self.allowed_channels.contains(id))
+        {
+            return Err("the channel is not allowed");
+        }
+        if roles.iter().any(|role| self.denied_roles.contains(role)) {
+            return Err("a role is denied");
+        }
+        if !self.allowed_roles.is_empty()
+            && !roles.iter().any(|role| self.allowed_roles.contains(role))
+        {
+            return Err("no role is allowed");
+        }
+        Ok(())
+    }
+
+    pub fn allows_command(&self, command: &str, roles: &[u64]) -> bool {
+        allowed_by(self.commands.get(command), roles)
+    }
+
+    pub fn allows_preset(&self, preset: &str, roles: &[u64]) -> bool {
+        allowed_by(self.presets.get(preset), roles)
+    }
+}
+
+fn allowed_by(allowed_roles: Option<&Vec<u64>>, roles: &[u64]) -> bool {
+    allowed_roles.is_none_or(|allowed_roles| roles.iter().any(|role| allowed_roles.contains(role)))
+}
+
+// Reads a channel, role or user from a mention like <#123>, <@&123> or <@123>, or from a plain id
+pub fn parse_id(argument: &str) -> Option<u64> {
+    argument
+        .trim_start_matches("<#")
+        .trim_start_matches("<@")
+        .trim_start_matches(['&', '!'])
+        .trim_end_matches('>')
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denials_win_and_empty_allow_lists_allow_everything() {
+        let mut rules = AccessRules::default();
+        assert!(rules.check(1, Some(10), &[100]).is_ok());
+
+        // Allowing a category allows its channels, denying one of them still wins
+        rules.allowed_channels.push(10);
+        rules.denied_channels.push(2);
+        assert!(rules.check(1, Some(10), &[100]).is_ok());
+        assert!(rules.check(2, Some(10), &[100]).is_err());
+        assert!(rules.check(3, Some(30), &[100]).is_err());
+
+        rules.allowed_roles.push(100);
+        rules.denied_roles.push(200);
+        assert!(rules.check(1, Some(10), &[100]).is_ok());
+        assert!(rules.check(1, Some(10), &[100, 200]).is_err());
+        assert!(rules.check(1, Some(10), &[300]).is_err());
+    }
+
+    #[test]
+    fn commands_and_presets_without_rules_are_open() {
+        let mut rules = AccessRules::default();
+        rules.commands.insert("summarize".to_string(), vec![100]);
+        rules.presets.insert("chef".to_string(), vec![200]);
+
+        assert!(rules.allows_command("mood", &[]));
+        assert!(rules.allows_command("summarize", &[100]));
+        assert!(!rules.allows_command("summarize", &[200]));
+        assert!(rules.allows_preset("chef", &[200]));
+        assert!(!rules.allows_preset("chef", &[100]));
+        assert_eq!(parse_id("<@&42>"), Some(42));
+        assert_eq!(parse_id("<#7>"), Some(7));
+        assert_eq!(parse_id("<@!9>"), Some(9));
+        assert_eq!(parse_id("general"), None);
+    }
+}
@@ -0,0 +1,135 @@
+use chatgpt::err::Error;
+use chatgpt::prelude::*;
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::time::Instant;
+
+use crate::conversation::Turn;
+use crate::metrics::METRICS;
+
+// Answers a conversation with the next assistant message
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    async fn send(&self, history: &[Turn]) -> Result<String>;
+
+    // Checks that the backend can be reached, for the readiness endpoint
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Sends conversations to the chat completions API directly, so user messages can carry their author's name
+pub struct ChatClient {
+    http: reqwest::Client,
+    api_key: String,
+    model: String,
+}
+
+impl ChatClient {
+    pub fn new(api_key: String, model: String) -> Self {
+        ChatClient {
+            http: reqwest::Client::new(),
+            api_key,
+            model,
+        }
+    }
+
+    // Reads CHAT_MODEL, defaulting to the same model as the rest of the bot
+    pub fn from_env(api_key: String) -> Self {
+        let model = std::env::var("CHAT_MODEL").unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
+        ChatClient::new(api_key, model)
+    }
+}
+
+#[async_trait]
+impl ChatBackend for ChatClient {
+    async fn send(&self, history: &[Turn]) -> Result<String> {
+        let started = Instant::now();
+        let response = self
+            .http
+            .post("https://api.openai.com/v1/chat/completions")
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "model": self.model, "messages": history }))
+            .send()
+            .await?;
+        METRICS
+            .backend_latency
+            .with_label_values(&[&self.model])
+            .observe(started.elapsed().as_secs_f64());
+        let response: Value = response.json().await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::BackendError {
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+                error_type: error["type"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+
+        let usage = &response["usage"];
+        METRICS.record_tokens(
+            &self.model,
+            usage["prompt_tokens"].as_u64().unwrap_or_default(),
+            usage["completion_tokens"].as_u64().unwrap_or_default(),
+        );
+
+        response["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|content| content.to_string())
+            .ok_or_else(|| Error::ParsingError(format!("Unexpected chat response: {}", response)))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let status = self
+            .http
+            .get(format!("https://api.openai.com/v1/models/{}", self.model))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?
+            .status();
+
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(Error::BackendError {
+                message: format!("Model lookup failed with {}", status),
+                error_type: "unreachable".to_string(),
+            }),
+        }
+    }
+}
+
+// Replies with a fixed answer and records every conversation it was sent, for tests
+#[cfg(test)]
+pub struct MockBackend {
+    // None fails every request
+    reply: Option<String>,
+    pub requests: std::sync::Mutex<Vec<Vec<Turn>>>,
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn new(reply: &str) -> Self {
+        MockBackend {
+            reply: Some(reply.to_string()),
+            requests: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn failing() -> Self {
+        MockBackend {
+            reply: None,
+            requests: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ChatBackend for MockBackend {
+    async fn send(&self, history: &[Turn]) -> Result<String> {
+        self.requests.lock().unwrap().push(history.to_vec());
+        self.reply.clone().ok_or_else(|| Error::BackendError {
+            message: "The mock backend always fails".to_string(),
+            error_type: "server_error".to_string(),
+        })
+    }
+}
@@ -0,0 +1,1307 @@
+use serenity::client::Context;
+use serenity::model::prelude::*;
+use tracing::{info, warn};
+
+use crate::access_control::{parse_id, AccessRules};
+use crate::guild_settings::Persona;
+use crate::handler::{Handler, ResetScope, UNDO_WINDOW_MINUTES};
+use crate::injection::InjectionAction;
+use crate::language::parse_language;
+use crate::moderation::ModerationAction;
+use crate::mood::Mood;
+use crate::permissions::member_roles;
+use crate::preset_selection::{
+    find_preset, validate_custom_prompt, ContentRating, DEFAULT_PRESET, PRESETS,
+};
+use crate::summarize::{chunk_history, fetch_history, SummaryRange};
+use crate::tone::{ToneBand, TonePolicy};
+use crate::user_memory::{RememberError, MAX_FACTS_PER_USER, MAX_FACT_CHARS};
+
+const MAX_DOCUMENT_BYTES: u64 = 1_000_000;
+
+// Text commands the bot understands, recognised by the first word of a message
+pub enum Command {
+    Summarize(String),
+    KnowledgeBase(String),
+    Remember(String),
+    Forget(String),
+    Memories,
+    Persona(String),
+    Mood,
+    Language(String),
+    Tone(String),
+    Injection(String),
+    Moderation(String),
+    Admin(String),
+    Reset(String),
+}
+
+// Names of the commands, as used in per-command access rules
+pub const COMMAND_NAMES: &[&str] = &[
+    "summarize",
+    "kb",
+    "remember",
+    "forget",
+    "memories",
+    "persona",
+    "mood",
+    "language",
+    "tone",
+    "injection",
+    "moderation",
+    "admin",
+    "reset",
+];
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Summarize(_) => "summarize",
+            Command::KnowledgeBase(_) => "kb",
+            Command::Remember(_) => "remember",
+            Command::Forget(_) => "forget",
+            Command::Memories => "memories",
+            Command::Persona(_) => "persona",
+            Command::Mood => "mood",
+            Command::Language(_) => "language",
+            Command::Tone(_) => "tone",
+            Command::Injection(_) => "injection",
+            Command::Moderation(_) => "moderation",
+            Command::Admin(_) => "admin",
+            Command::Reset(_) => "reset",
+        }
+    }
+}
+
+// The bot's name is needed for the old reset, which only counts in messages addressed to the bot
+pub fn parse_command(content: &str, bot_name: &str) -> Option<Command> {
+    let content = content.trim();
+    let (name, argument) = content
+        .split_once(char::is_whitespace)
+        .unwrap_or((content, ""));
+    let argument = argument.trim().to_string();
+
+    match name.to_lowercase().as_str() {
+        "/summarize" => Some(Command::Summarize(argument)),
+        "/kb" => Some(Command::KnowledgeBase(argument)),
+        "/remember" => Some(Command::Remember(argument)),
+        "/forget" => Some(Command::Forget(argument)),
+        "/memories" => Some(Command::Memories),
+        "/persona" => Some(Command::Persona(argument)),
+        "/mood" => Some(Command::Mood),
+        "/language" => Some(Command::Language(argument)),
+        "/tone" => Some(Command::Tone(argument)),
+        "/injection" => Some(Command::Injection(argument)),
+        "/moderation" => Some(Command::Moderation(argument)),
+        "/admin" => Some(Command::Admin(argument)),
+        "/reset" => Some(Command::Reset(argument)),
+        // The old way to reset a channel, anywhere in a message that names the bot
+        _ if content.to_lowercase().contains("!reset!")
+            && content.to_lowercase().contains(&bot_name.to_lowercase()) =>
+        {
+            Some(Command::Reset(String::new()))
+        }
+        _ => None,
+    }
+}
+
+impl Handler {
+    pub async fn run_command(&self, ctx: &Context, msg: &Message, command: Command) {
+        // Commands with role rules are limited to those roles, admins can always run every command
+        if let Some(guild_id) = msg.guild_id {
+            let access = self.settings.get(guild_id.0).await.access;
+            if !access.allows_command(command.name(), &member_roles(msg))
+                && !self.is_admin(ctx, msg).await
+            {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    &format!("You don't have a role that can use /{}.", command.name()),
+                )
+                .await;
+                return;
+            }
+        }
+
+        match command {
+            Command::Summarize(argument) => self.summarize_command(ctx, msg, &argument).await,
+            Command::KnowledgeBase(argument) => {
+                self.knowledge_base_command(ctx, msg, &argument).await
+            }
+            Command::Remember(fact) => self.remember_command(ctx, msg, &fact).await,
+            Command::Forget(argument) => self.forget_command(ctx, msg, &argument).await,
+            Command::Memories => self.memories_command(ctx, msg).await,
+            Command::Persona(argument) => self.persona_command(ctx, msg, &argument).await,
+            Command::Mood => self.mood_command(ctx, msg).await,
+            Command::Language(argument) => self.language_command(ctx, msg, &argument).await,
+            Command::Tone(argument) => self.tone_command(ctx, msg, &argument).await,
+            Command::Injection(argument) => self.injection_command(ctx, msg, &argument).await,
+            Command::Moderation(argument) => self.moderation_command(ctx, msg, &argument).await,
+            Command::Admin(argument) => self.admin_command(ctx, msg, &argument).await,
+            Command::Reset(argument) => self.reset_command(ctx, msg, &argument).await,
+        }
+    }
+
+    async fn summarize_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let range = match SummaryRange::parse(argument) {
+            Some(range) => range,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Usage: /summarize [count | 30m | 2h | 1d]",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let _ = msg.channel_id.broadcast_typing(&ctx.http).await;
+
+        let history = match fetch_history(&ctx.http, msg.channel_id, msg.id, &range).await {
+            Ok(history) => history,
+            Err(e) => {
+                warn!("Failed to fetch channel history: {}", e);
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "I couldn't read this channel's history.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let chunks = chunk_history(&history);
+        if chunks.is_empty() {
+            self.say(ctx, msg.channel_id, "There is nothing to summarize.")
+                .await;
+            return;
+        }
+
+        info!(
+            "Summarizing {} messages in {} chunks for channel: {}",
+            history.len(),
+            chunks.len(),
+            msg.channel_id
+        );
+
+        match self
+            .summarize(
+                msg.guild_id.map(|guild_id| guild_id.0),
+                msg.channel_id.0,
+                chunks,
+            )
+            .await
+        {
+            Ok(summary) => self.say(ctx, msg.channel_id, &summary).await,
+            Err(e) => {
+                warn!("Failed to summarize: {}", e);
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "I couldn't summarize this channel right now.",
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn knowledge_base_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "The knowledge base only exists in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let (subcommand, document) = argument.split_once(' ').unwrap_or((argument, ""));
+        let document = document.trim();
+
+        match subcommand.to_lowercase().as_str() {
+            "list" => {
+                let documents = self.knowledge.documents(guild_id).await;
+                let reply = if documents.is_empty() {
+                    "The knowledge base is empty.".to_string()
+                } else {
+                    documents
+                        .iter()
+                        .map(|(document, chunks)| format!("- {} ({} chunks)", document, chunks))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                self.say(ctx, msg.channel_id, &reply).await;
+            }
+            "add" if self.is_admin(ctx, msg).await => self.add_documents(ctx, msg, guild_id).await,
+            "remove" if self.is_admin(ctx, msg).await => {
+                let reply = if self.knowledge.remove_document(guild_id, document).await {
+                    format!("Removed {} from the knowledge base.", document)
+                } else {
+                    format!("There is no document called {}.", document)
+                };
+                self.say(ctx, msg.channel_id, &reply).await;
+            }
+            "add" | "remove" => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Only server admins can change the knowledge base.",
+                )
+                .await;
+            }
+            _ => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Usage: /kb list | /kb add (with .md or .txt attachments) | /kb remove <document>",
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn add_documents(&self, ctx: &Context, msg: &Message, guild_id: u64) {
+        if msg.attachments.is_empty() {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Attach one or more .md or .txt files to add them.",
+            )
+            .await;
+            return;
+        }
+
+        for attachment in &msg.attachments {
+            let name = attachment.filename.clone();
+            let supported = [".md", ".markdown", ".txt"]
+                .iter()
+                .any(|extension| name.to_lowercase().ends_with(extension));
+
+            let reply = if !supported {
+                format!("Skipped {}: only .md and .txt files are supported.", name)
+            } else if attachment.size > MAX_DOCUMENT_BYTES {
+                format!("Skipped {}: documents can be at most 1 MB.", name)
+            } else {
+                match attachment.download().await.map(String::from_utf8) {
+                    Ok(Ok(content)) => {
+                        match self.knowledge.add_document(guild_id, &name, &content).await {
+                            Ok(chunks) => format!("Added {} ({} chunks).", name, chunks),
+                            Err(e) => {
+                                warn!("Failed to index {}: {}", name, e);
+                                format!("Failed to index {}.", name)
+                            }
+                        }
+                    }
+                    Ok(Err(_)) => format!("Skipped {}: it is not valid UTF-8 text.", name),
+                    Err(e) => {
+                        warn!("Failed to download {}: {}", name, e);
+                        format!("Failed to download {}.", name)
+                    }
+                }
+            };
+
+            self.say(ctx, msg.channel_id, &reply).await;
+        }
+    }
+
+    async fn remember_command(&self, ctx: &Context, msg: &Message, fact: &str) {
+        if fact.is_empty() {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Usage: /remember <something about you>",
+            )
+            .await;
+            return;
+        }
+
+        let reply = match self
+            .user_memory
+            .remember(memory_key(msg), msg.author.id.0, fact)
+            .await
+        {
+            Ok(count) => format!(
+                "Got it, I'll remember that. ({}/{})",
+                count, MAX_FACTS_PER_USER
+            ),
+            Err(RememberError::TooLong) => format!(
+                "That's too long to remember, keep it under {} characters.",
+                MAX_FACT_CHARS
+            ),
+            Err(RememberError::TooMany) => format!(
+                "I can only remember {} things about you, use /forget to make room.",
+                MAX_FACTS_PER_USER
+            ),
+        };
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn forget_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let key = memory_key(msg);
+
+        let reply = if argument.eq_ignore_ascii_case("all") {
+            let removed = self.user_memory.forget_all(key, msg.author.id.0).await;
+            self.memory.forget_author(key, msg.author.id.0).await;
+
+            // The ongoing conversations of the server still hold what the user said
+            let channels = match msg.guild_id {
+                Some(guild_id) => match guild_id.channels(&ctx.http).await {
+                    Ok(channels) => channels.keys().map(|channel| channel.0).collect(),
+                    Err(e) => {
+                        warn!("Failed to fetch the channels of guild {}: {}", guild_id, e);
+                        vec![msg.channel_id.0]
+                    }
+                },
+                None => vec![msg.channel_id.0],
+            };
+            self.forget_speaker(&channels, msg.author.id.0).await;
+            self.moods
+                .forget_user(msg.guild_id.map(|guild_id| guild_id.0), msg.author.id.0)
+                .await;
+
+            format!(
+                "I've forgotten everything about you ({} saved memories and your past messages).",
+                removed
+            )
+        } else if let Ok(position) = argument.parse::<usize>() {
+            match self
+                .user_memory
+                .forget(key, msg.author.id.0, position)
+                .await
+            {
+                Some(fact) => format!("Forgotten: {}", fact.text),
+                None => format!("There is no memory number {}, see /memories.", position),
+            }
+        } else {
+            "Usage: /forget <number> | /forget all".to_string()
+        };
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn memories_command(&self, ctx: &Context, msg: &Message) {
+        let facts = self
+            .user_memory
+            .facts(memory_key(msg), msg.author.id.0)
+            .await;
+
+        let reply = if facts.is_empty() {
+            "I don't have any memories about you. Use /remember to add one.".to_string()
+        } else {
+            facts
+                .iter()
+                .enumerate()
+                .map(|(index, fact)| format!("{}. {}", index + 1, fact.text))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn persona_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(ctx, msg.channel_id, "Personas can only be set in servers.")
+                    .await;
+                return;
+            }
+        };
+
+        let (subcommand, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+        let rest = rest.trim();
+        let subcommand = subcommand.to_lowercase();
+
+        // Whether this channel's conversation switched persona, so the new persona can acknowledge it
+        let mut switched_here = false;
+
+        let reply = match subcommand.as_str() {
+            "" | "list" => self.describe_personas(ctx, msg, guild_id).await,
+            "use" => {
+                let (reply, switched) = self.use_persona(ctx, msg, guild_id, rest).await;
+                switched_here = switched;
+                reply
+            }
+            "pin" | "unpin" | "switching" | "acknowledge" | "allow" | "disallow"
+                if !self.is_admin(ctx, msg).await =>
+            {
+                "Only server admins can pin personas.".to_string()
+            }
+            // Restricted presets on the allowlist can be used in every channel of the guild
+            "allow" | "disallow" => match find_preset(rest) {
+                Some(preset) if preset.rating == ContentRating::Restricted => {
+                    let allow = subcommand == "allow";
+                    self.settings
+                        .update(guild_id, |settings| {
+                            settings.allowed_presets.retain(|name| name != preset.name);
+                            if allow {
+                                settings.allowed_presets.push(preset.name.to_string());
+                            }
+                        })
+                        .await;
+                    if allow {
+                        format!("{} can now be used in every channel.", preset.name)
+                    } else {
+                        format!("{} can now only be used in NSFW channels.", preset.name)
+                    }
+                }
+                Some(preset) => format!("{} is not restricted, it can be used anywhere.", preset.name),
+                None => format!("Usage: /persona {} <restricted preset>", subcommand),
+            },
+            "pin" | "unpin" => {
+                // "category" targets the channel's category instead of the channel itself
+                let (target, rest) = match rest.split_once(' ').unwrap_or((rest, "")) {
+                    ("category", rest) => match self.channel_category(ctx, msg.channel_id).await {
+                        Some(category_id) => (category_id, rest.trim()),
+                        None => {
+                            self.say(ctx, msg.channel_id, "This channel is not in a category.")
+                                .await;
+                            return;
+                        }
+                    },
+                    _ => (msg.channel_id.0, rest),
+                };
+
+                if subcommand == "unpin" {
+                    self.settings
+                        .update(guild_id, |settings| {
+                            if let Some(channel) = settings.channels.get_mut(&target) {
+                                channel.persona = None;
+                            }
+                        })
+                        .await;
+                    switched_here = self
+                        .switch_personas_in(guild_id, target)
+                        .await
+                        .contains(&msg.channel_id.0);
+                    "Unpinned the persona, it will be picked automatically again.".to_string()
+                } else {
+                    let persona = parse_persona(rest);
+                    if let Some(Persona::Custom(prompt)) = &persona {
+                        if let Err(e) = validate_custom_prompt(prompt) {
+                            self.say(
+                                ctx,
+                                msg.channel_id,
+                                &format!("That prompt can't be used: {}", e),
+                            )
+                            .await;
+                            return;
+                        }
+                    }
+
+                    // A category pin can reach channels that aren't NSFW, so only the allowlist counts there
+                    let nsfw = target == msg.channel_id.0
+                        && self.channel_nsfw(ctx, msg.channel_id).await;
+                    let access = self.settings.get(guild_id).await.preset_access(nsfw, None);
+                    match persona {
+                        Some(Persona::Preset(name))
+                            if find_preset(&name).is_some_and(|preset| !access.allows(preset)) =>
+                        {
+                            format!("{} can only be pinned in NSFW channels, unless it is allowed with /persona allow.", name)
+                        }
+                        Some(persona) => {
+                            let reply = format!("Pinned persona: {}", persona);
+                            self.settings
+                                .update(guild_id, |settings| {
+                                    settings.channels.entry(target).or_default().persona =
+                                        Some(persona)
+                                })
+                                .await;
+                            switched_here = self
+                                .switch_personas_in(guild_id, target)
+                                .await
+                                .contains(&msg.channel_id.0);
+                            reply
+                        }
+                        None => "Usage: /persona pin [category] <preset | custom <prompt>>, see /persona list for presets.".to_string(),
+                    }
+                }
+            }
+            "switching" | "acknowledge" if parse_toggle(rest).is_none() => {
+                format!("Usage: /persona {} on|off", subcommand)
+            }
+            "acknowledge" => {
+                let acknowledge = parse_toggle(rest) == Some(true);
+                self.settings
+                    .update(guild_id, |settings| {
+                        settings
+                            .channels
+                            .entry(msg.channel_id.0)
+                            .or_default()
+                            .acknowledge_persona_switch = Some(acknowledge)
+                    })
+                    .await;
+                format!(
+                    "New personas will {} introduce themselves in this channel.",
+                    if acknowledge { "now" } else { "no longer" }
+                )
+            }
+            "switching" => {
+                let allow = parse_toggle(rest) == Some(true);
+                self.settings
+                    .update(guild_id, |settings| {
+                        settings
+                            .channels
+                            .entry(msg.channel_id.0)
+                            .or_default()
+                            .allow_persona_switch = Some(allow)
+                    })
+                    .await;
+                if !allow {
+                    self.chosen_personas.lock().await.remove(&msg.channel_id.0);
+                }
+                format!(
+                    "Users can {} switch personas in this channel.",
+                    if allow { "now" } else { "no longer" }
+                )
+            }
+            _ => "Usage: /persona list | /persona use <preset> | /persona pin [category] <preset | custom <prompt>> | /persona unpin [category] | /persona switching on|off | /persona acknowledge on|off | /persona allow|disallow <restricted preset>".to_string(),
+        };
+
+        self.say(ctx, msg.channel_id, &reply).await;
+
+        if switched_here {
+            let category_id = self.channel_category(ctx, msg.channel_id).await;
+            let settings = self.settings.get(guild_id).await;
+            if settings.acknowledges_persona_switch(msg.channel_id.0, category_id) {
+                self.acknowledge_switch(ctx, msg.channel_id, settings.moderation.output)
+                    .await;
+            }
+        }
+    }
+
+    async fn mood_command(&self, ctx: &Context, msg: &Message) {
+        let describe = |who: &str, mood: Option<Mood>| match mood {
+            Some(mood) => format!(
+                "{}: {} ({:.2}) {}",
+                who,
+                mood.label(),
+                mood.average,
+                mood.sparkline()
+            ),
+            None => format!("{}: no messages yet", who),
+        };
+
+        let reply = format!(
+            "{}\n{}",
+            describe("Channel", self.moods.channel(msg.channel_id.0).await),
+            describe(
+                "You",
+                self.moods
+                    .user(msg.guild_id.map(|guild_id| guild_id.0), msg.author.id.0)
+                    .await
+            )
+        );
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn language_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "The reply language can only be set in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let reply = if argument.is_empty() {
+            let category_id = self.channel_category(ctx, msg.channel_id).await;
+            match self
+                .settings
+                .get(guild_id)
+                .await
+                .language(msg.channel_id.0, category_id)
+            {
+                Some(language) => format!(
+                    "Replies in this channel are always in {}.",
+                    language.eng_name()
+                ),
+                None => "Replies in this channel follow the language of each message.".to_string(),
+            }
+        } else if !self.is_admin(ctx, msg).await {
+            "Only server admins can set the reply language.".to_string()
+        } else {
+            // "auto" goes back to replying in the language each message is written in
+            let language = match argument.to_lowercase().as_str() {
+                "auto" => None,
+                _ => match parse_language(argument) {
+                    Some(language) => Some(language),
+                    None => {
+                        self.say(
+                            ctx,
+                            msg.channel_id,
+                            &format!("I don't know a language called {}. Usage: /language [<language> | auto]", argument),
+                        )
+                        .await;
+                        return;
+                    }
+                },
+            };
+
+            self.settings
+                .update(guild_id, |settings| {
+                    settings
+                        .channels
+                        .entry(msg.channel_id.0)
+                        .or_default()
+                        .language = language.map(|language| language.code().to_string())
+                })
+                .await;
+            match language {
+                Some(language) => format!(
+                    "Replies in this channel will now be in {}.",
+                    language.eng_name()
+                ),
+                None => "Replies in this channel will now follow the language of each message."
+                    .to_string(),
+            }
+        };
+
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn tone_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "The tone policy can only be set in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        if argument.is_empty() {
+            let tone = self.settings.get(guild_id).await.tone;
+            self.say(ctx, msg.channel_id, &format!("Tone policy: {}", tone))
+                .await;
+            return;
+        }
+
+        if !self.is_admin(ctx, msg).await {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Only server admins can change the tone policy.",
+            )
+            .await;
+            return;
+        }
+
+        let (subcommand, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+        let rest = rest.trim();
+
+        let reply = match subcommand.to_lowercase().as_str() {
+            "mirror" => {
+                self.settings
+                    .update(guild_id, |settings| settings.tone = TonePolicy::Mirror)
+                    .await;
+                "Replies will now mirror the mood of the conversation, including anger.".to_string()
+            }
+            "deescalate" | "de-escalate" => {
+                self.settings
+                    .update(guild_id, |settings| settings.tone = TonePolicy::Deescalate)
+                    .await;
+                "Replies will now stay calm when the conversation turns negative.".to_string()
+            }
+            "fixed" if !rest.is_empty() => {
+                self.settings
+                    .update(guild_id, |settings| {
+                        settings.tone = TonePolicy::Fixed(rest.to_string())
+                    })
+                    .await;
+                "Replies will now always use that tone.".to_string()
+            }
+            // Adds or replaces the band for a score, switching the guild to custom bands
+            "band" => match rest.split_once(' ') {
+                Some((score, tone)) if !tone.trim().is_empty() => match score.parse::<f64>() {
+                    Ok(score) if (-1.0..=1.0).contains(&score) => {
+                        let tone = tone.trim().to_string();
+                        self.settings
+                            .update(guild_id, |settings| {
+                                let mut bands = match &settings.tone {
+                                    TonePolicy::Custom(bands) => bands.clone(),
+                                    _ => Vec::new(),
+                                };
+                                bands.retain(|band| band.score != score);
+                                bands.push(ToneBand { score, tone });
+                                bands.sort_by(|a, b| b.score.total_cmp(&a.score));
+                                settings.tone = TonePolicy::Custom(bands);
+                            })
+                            .await;
+                        format!("Set the tone for sentiment scores around {:+.2}.", score)
+                    }
+                    _ => "The score must be a number between -1 and 1.".to_string(),
+                },
+                _ => "Usage: /tone band <score between -1 and 1> <tone instruction>".to_string(),
+            },
+            _ => "Usage: /tone | /tone mirror | /tone deescalate | /tone fixed <tone instruction> | /tone band <score> <tone instruction>".to_string(),
+        };
+
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn injection_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Prompt injection handling can only be set in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        if argument.is_empty() {
+            let action = self.settings.get(guild_id).await.injection;
+            self.say(
+                ctx,
+                msg.channel_id,
+                &format!(
+                    "Messages that look like prompt injection attempts: {}",
+                    action
+                ),
+            )
+            .await;
+            return;
+        }
+
+        if !self.is_admin(ctx, msg).await {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Only server admins can change how prompt injection attempts are handled.",
+            )
+            .await;
+            return;
+        }
+
+        let (action, reply) = match argument.to_lowercase().as_str() {
+            "refuse" => (
+                InjectionAction::Refuse,
+                "Messages that look like prompt injection attempts will now be refused.",
+            ),
+            "warn" => (
+                InjectionAction::Warn,
+                "Messages that look like prompt injection attempts will now be answered with extra care.",
+            ),
+            "log" => (
+                InjectionAction::Log,
+                "Messages that look like prompt injection attempts will now only be logged.",
+            ),
+            _ => {
+                self.say(ctx, msg.channel_id, "Usage: /injection [refuse | warn | log]")
+                    .await;
+                return;
+            }
+        };
+
+        self.settings
+            .update(guild_id, |settings| settings.injection = action)
+            .await;
+        self.say(ctx, msg.channel_id, reply).await;
+    }
+
+    async fn moderation_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Moderation can only be set up in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        if argument.is_empty() {
+            let moderation = self.settings.get(guild_id).await.moderation;
+            self.say(
+                ctx,
+                msg.channel_id,
+                &format!(
+                    "Flagged messages: {}\nFlagged replies: {}",
+                    moderation.input, moderation.output
+                ),
+            )
+            .await;
+            return;
+        }
+
+        if !self.is_admin(ctx, msg).await {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Only server admins can change moderation.",
+            )
+            .await;
+            return;
+        }
+
+        let (stage, action) = argument.split_once(' ').unwrap_or((argument, ""));
+        let action = ModerationAction::parse(action.trim());
+        let reply = match (stage.to_lowercase().as_str(), action) {
+            ("input", Some(action)) => {
+                self.settings
+                    .update(guild_id, |settings| settings.moderation.input = action)
+                    .await;
+                format!("Flagged messages will now be handled with: {}", action)
+            }
+            ("output", Some(action)) => {
+                self.settings
+                    .update(guild_id, |settings| settings.moderation.output = action)
+                    .await;
+                format!("Flagged replies will now be handled with: {}", action)
+            }
+            _ => "Usage: /moderation [input | output] [block | redact | warn]".to_string(),
+        };
+
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn admin_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Access rules can only be set in servers.",
+                )
+                .await;
+                return;
+            }
+        };
+
+        if !self.is_admin(ctx, msg).await {
+            self.say(
+                ctx,
+                msg.channel_id,
+                "Only server admins can manage access rules.",
+            )
+            .await;
+            return;
+        }
+
+        let arguments: Vec<&str> = argument.split_whitespace().collect();
+        let usage = "Usage: /admin | /admin allow|deny|clear channel [#channel] | /admin allow|deny|clear category [id] | /admin allow|deny|clear role <@role> | /admin command <command> <@role...>|everyone | /admin preset <preset> <@role...>|everyone | /admin block|unblock|unmute <@user> | /admin modlog <#channel>|off";
+
+        let reply = match arguments.as_slice() {
+            [] => {
+                let settings = self.settings.get(guild_id).await;
+                format!(
+                    "{}\nBlocked users: {}\nMod log: {}",
+                    describe_access(&settings.access),
+                    if settings.blocked_users.is_empty() {
+                        "none".to_string()
+                    } else {
+                        settings
+                            .blocked_users
+                            .iter()
+                            .map(|id| id.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    },
+                    settings
+                        .mod_log_channel
+                        .map_or("off".to_string(), |channel| format!("<#{}>", channel))
+                )
+            }
+            [action @ ("block" | "unblock" | "unmute"), user] => match parse_id(user) {
+                Some(user_id) => {
+                    let reply = match *action {
+                        "unmute" if self.abuse.unmute(guild_id, user_id).await => {
+                            format!("Unmuted user {}.", user_id)
+                        }
+                        "unmute" => format!("User {} is not muted.", user_id),
+                        _ => {
+                            let block = *action == "block";
+                            self.settings
+                                .update(guild_id, |settings| {
+                                    settings.blocked_users.retain(|blocked| *blocked != user_id);
+                                    if block {
+                                        settings.blocked_users.push(user_id);
+                                    }
+                                })
+                                .await;
+                            format!(
+                                "{}ed user {}.",
+                                if block { "Block" } else { "Unblock" },
+                                user_id
+                            )
+                        }
+                    };
+                    self.mod_log(guild_id, &format!("{} (by {})", reply, msg.author.name))
+                        .await;
+                    reply
+                }
+                None => usage.to_string(),
+            },
+            ["modlog", channel] => {
+                let channel = match *channel {
+                    "off" => Some(None),
+                    channel => parse_id(channel).map(Some),
+                };
+                match channel {
+                    Some(channel) => {
+                        self.settings
+                            .update(guild_id, |settings| settings.mod_log_channel = channel)
+                            .await;
+                        match channel {
+                            Some(channel) => {
+                                format!("Mutes and blocks will now be reported in <#{}>.", channel)
+                            }
+                            None => "Mutes and blocks will no longer be reported.".to_string(),
+                        }
+                    }
+                    None => usage.to_string(),
+                }
+            }
+            [action @ ("allow" | "deny" | "clear"), kind @ ("channel" | "category" | "role"), rest @ ..] =>
+            {
+                // Channels and categories default to the one the command is sent in
+                let id = match (*kind, rest.first()) {
+                    (_, Some(id)) => parse_id(id),
+                    ("channel", None) => Some(msg.channel_id.0),
+                    ("category", None) => self.channel_category(ctx, msg.channel_id).await,
+                    _ => None,
+                };
+                match id {
+                    Some(id) => {
+                        self.settings
+                            .update(guild_id, |settings| {
+                                let access = &mut settings.access;
+                                let (allowed, denied) = match *kind {
+                                    "role" => (&mut access.allowed_roles, &mut access.denied_roles),
+                                    _ => {
+                                        (&mut access.allowed_channels, &mut access.denied_channels)
+                                    }
+                                };
+                                allowed.retain(|allowed| *allowed != id);
+                                denied.retain(|denied| *denied != id);
+                                match *action {
+                                    "allow" => allowed.push(id),
+                                    "deny" => denied.push(id),
+                                    _ => {}
+                                }
+                            })
+                            .await;
+                        match *action {
+                            "allow" => format!("Allowed {} {}.", kind, id),
+                            "deny" => format!("Denied {} {}.", kind, id),
+                            _ => format!("Cleared the rules for {} {}.", kind, id),
+                        }
+                    }
+                    None => usage.to_string(),
+                }
+            }
+            [kind @ ("command" | "preset"), name, roles @ ..] if !roles.is_empty() => {
+                let name = name.trim_start_matches('/').to_lowercase();
+                let known = match *kind {
+                    "command" => COMMAND_NAMES.contains(&name.as_str()) && name != "admin",
+                    _ => find_preset(&name).is_some(),
+                };
+                let role_ids: Option<Vec<u64>> = match roles {
+                    ["everyone"] => Some(Vec::new()),
+                    _ => roles.iter().map(|role| parse_id(role)).collect(),
+                };
+                match role_ids {
+                    _ if !known => format!("There is no {} called {}.", kind, name),
+                    Some(role_ids) => {
+                        let everyone = role_ids.is_empty();
+                        self.settings
+                            .update(guild_id, |settings| {
+                                let rules = match *kind {
+                                    "command" => &mut settings.access.commands,
+                                    _ => &mut settings.access.presets,
+                                };
+                                if everyone {
+                                    rules.remove(&name);
+                                } else {
+                                    rules.insert(name.clone(), role_ids);
+                                }
+                            })
+                            .await;
+                        if everyone {
+                            format!("Everyone can now use the {} {}.", kind, name)
+                        } else {
+                            format!("Only the listed roles can now use the {} {}.", kind, name)
+                        }
+                    }
+                    None => usage.to_string(),
+                }
+            }
+            _ => usage.to_string(),
+        };
+
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn reset_command(&self, ctx: &Context, msg: &Message, argument: &str) {
+        let channel_id = msg.channel_id.0;
+        let scope = match argument.to_lowercase().as_str() {
+            "" | "channel" => ResetScope::Channels(vec![channel_id]),
+            "mine" => ResetScope::Mine {
+                channel_id,
+                speaker: self.speakers.lock().await.name(
+                    channel_id,
+                    msg.author.id.0,
+                    &msg.author.name,
+                ),
+            },
+            "guild" => match msg.guild_id {
+                None => {
+                    self.say(ctx, msg.channel_id, "Only servers can be reset as a whole.")
+                        .await;
+                    return;
+                }
+                Some(_) if !self.is_admin(ctx, msg).await => {
+                    self.say(
+                        ctx,
+                        msg.channel_id,
+                        "Only admins can reset every conversation in the server.",
+                    )
+                    .await;
+                    return;
+                }
+                Some(guild_id) => match guild_id.channels(&ctx.http).await {
+                    Ok(channels) => {
+                        ResetScope::Channels(channels.keys().map(|channel| channel.0).collect())
+                    }
+                    Err(e) => {
+                        warn!("Failed to fetch the channels of guild {}: {}", guild_id, e);
+                        self.say(
+                            ctx,
+                            msg.channel_id,
+                            "I couldn't list this server's channels.",
+                        )
+                        .await;
+                        return;
+                    }
+                },
+            },
+            "undo" => {
+                let admin = self.is_admin(ctx, msg).await;
+                let reply = match self.undo_reset(channel_id, msg.author.id.0, admin).await {
+                    Ok(1) => "The conversation is back.".to_string(),
+                    Ok(count) => format!("{} conversations are back.", count),
+                    Err(reason) => reason.to_string(),
+                };
+                self.say(ctx, msg.channel_id, &reply).await;
+                return;
+            }
+            _ => {
+                self.say(
+                    ctx,
+                    msg.channel_id,
+                    "Usage: /reset [channel | mine | guild | undo]",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let mine = matches!(scope, ResetScope::Mine { .. });
+        let reply = match self.reset(scope, channel_id, msg.author.id.0).await {
+            0 => "There was nothing to reset.".to_string(),
+            count => format!(
+                "{} Use /reset undo within {} minutes to bring it back.",
+                match (mine, count) {
+                    (true, _) => "Your messages were removed from this conversation.".to_string(),
+                    (false, 1) => "The conversation was reset.".to_string(),
+                    (false, count) => format!("{} conversations were reset.", count),
+                },
+                UNDO_WINDOW_MINUTES
+            ),
+        };
+        self.say(ctx, msg.channel_id, &reply).await;
+    }
+
+    async fn describe_personas(&self, ctx: &Context, msg: &Message, guild_id: u64) -> String {
+        let category_id = self.channel_category(ctx, msg.channel_id).await;
+        let settings = self.settings.get(guild_id).await;
+
+        let current = match self.chosen_personas.lock().await.get(&msg.channel_id.0) {
+            Some(persona) if settings.allows_persona_switch(msg.channel_id.0, category_id) => {
+                format!("Current persona: {} (chosen by a user)", persona)
+            }
+            _ => match settings.pinned_persona(msg.channel_id.0, category_id) {
+                Some(persona) => format!("Current persona: {} (pinned)", persona),
+                None => "Current persona: picked automatically".to_string(),
+            },
+        };
+
+        // Restricted presets are only listed where they can be used, and to members who can use them
+        let access = settings.preset_access(
+            self.channel_nsfw(ctx, msg.channel_id).await,
+            Some(&member_roles(msg)),
+        );
+        let names: Vec<&str> = std::iter::once(&DEFAULT_PRESET)
+            .chain(PRESETS)
+            .filter(|preset| access.allows(preset))
+            .map(|preset| preset.name)
+            .collect();
+
+        format!("{}\nPresets: {}", current, names.join(", "))
+    }
+
+    // Returns the reply and whether an ongoing conversation was switched
+    async fn use_persona(
+        &self,
+        ctx: &Context,
+        msg: &Message,
+        guild_id: u64,
+        name: &str,
+    ) -> (String, bool) {
+        let category_id = self.channel_category(ctx, msg.channel_id).await;
+        let settings = self.settings.get(guild_id).await;
+
+        if !settings.allows_persona_switch(msg.channel_id.0, category_id) {
+            return (
+                "Switching personas is not allowed in this channel.".to_string(),
+                false,
+            );
+        }
+
+        // Users can only pick built-in presets, custom prompts are for admins
+        let access = settings.preset_access(
+            self.channel_nsfw(ctx, msg.channel_id).await,
+            Some(&member_roles(msg)),
+        );
+        let persona = match find_preset(name) {
+            Some(preset) if !access.allows_role(preset) => {
+                return (
+                    format!("You don't have a role that can use {}.", preset.name),
+                    false,
+                )
+            }
+            Some(preset) if !access.allows_rating(preset) => {
+                return (
+                    format!("{} can only be used in NSFW channels.", preset.name),
+                    false,
+                )
+            }
+            Some(preset) => Persona::Preset(preset.name.to_string()),
+            None => {
+                return (
+                    format!("There is no preset called {}, see /persona list.", name),
+                    false,
+                )
+            }
+        };
+
+        let reply = format!("Switched to persona: {}", persona);
+        self.chosen_personas
+            .lock()
+            .await
+            .insert(msg.channel_id.0, persona);
+
+        let switched = !self
+            .switch_personas_in(guild_id, msg.channel_id.0)
+            .await
+            .is_empty();
+        (reply, switched)
+    }
+}
+
+fn parse_toggle(argument: &str) -> Option<bool> {
+    match argument.to_lowercase().as_str() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+// Parses "<preset name>" or "custom <prompt>"
+fn parse_persona(argument: &str) -> Option<Persona> {
+    let (first, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+
+    if first.eq_ignore_ascii_case("custom") && !rest.trim().is_empty() {
+        return Some(Persona::Custom(rest.trim().to_string()));
+    }
+
+    find_preset(argument).map(|preset| Persona::Preset(preset.name.to_string()))
+}
+
+// Same keying as QueuedMessage::memory_key, guild-wide with a separate store for direct messages
+fn memory_key(msg: &Message) -> u64 {
+    msg.guild_id.map_or(msg.channel_id.0, |guild_id| guild_id.0)
+}
+
+fn describe_access(access: &AccessRules) -> String {
+    // Roles are shown by id, mentioning them would ping their members
+    let list = |ids: &[u64], mention: bool| {
+        if ids.is_empty() {
+            "none".to_string()
+        } else {
+            ids.iter()
+                .map(|id| {
+                    if mention {
+                        format!("<#{}>", id)
+                    } else {
+                        id.to_string()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+    let roles_for = |rules: &std::collections::HashMap<String, Vec<u64>>| {
+        let mut rules: Vec<String> = rules
+            .iter()
+            .map(|(name, roles)| format!("{} ({})", name, list(roles, false)))
+            .collect();
+        rules.sort();
+        if rules.is_empty() {
+            "none".to_string()
+        } else {
+            rules.join(", ")
+        }
+    };
+
+    format!(
+        "Allowed channels: {}\nDenied channels: {}\nAllowed roles: {}\nDenied roles: {}\nCommands limited to roles: {}\nPresets limited to roles: {}",
+        list(&access.allowed_channels, true),
+        list(&access.denied_channels, true),
+        list(&access.allowed_roles, false),
+        list(&access.denied_roles, false),
+        roles_for(&access.commands),
+        roles_for(&access.presets),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_old_reset_only_counts_when_addressed_to_the_bot() {
+        assert!(matches!(
+            parse_command("GPTBot !reset! please", "gptbot"),
+            Some(Command::Reset(argument)) if argument.is_empty()
+        ));
+        assert!(parse_command("the bot answers !reset! with a reset", "gptbot").is_none());
+        assert!(matches!(
+            parse_command("/reset mine", "gptbot"),
+            Some(Command::Reset(argument)) if argument == "mine"
+        ));
+    }
+}
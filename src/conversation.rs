@@ -0,0 +1,374 @@
+use chatgpt::types::Role;
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{error, info, warn};
+
+use crate::storage::DataDir;
+
+// A message in a channel's conversation. Users are told apart by the name field instead of a prefix in the content.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Turn {
+    pub role: Role,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Turn {
+    pub fn system(content: impl Into<String>) -> Self {
+        Turn {
+            role: Role::System,
+            content: content.into(),
+            name: None,
+        }
+    }
+
+    pub fn user(name: &str, content: impl Into<String>) -> Self {
+        Turn {
+            role: Role::User,
+            content: content.into(),
+            name: Some(name.to_string()),
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Turn {
+            role: Role::Assistant,
+            content: content.into(),
+            name: None,
+        }
+    }
+}
+
+// The history of a channel's conversation, starting with its system message
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conversation {
+    pub history: Vec<Turn>,
+}
+
+// Conversations live in memory, they are only written out on shutdown so a restart can pick them up again.
+// Speaker names are saved with them, so users in a restored conversation keep the names it knows them by.
+pub fn save_conversations(
+    data: &DataDir,
+    conversations: &HashMap<u64, (Conversation, DateTime<Utc>)>,
+    speakers: &SpeakerNames,
+) {
+    save(data, "conversations.json", conversations);
+    save(data, "speakers.json", speakers);
+    info!("Saved {} conversations", conversations.len());
+}
+
+// The snapshot is removed once it is loaded, a crash later on must not bring back conversations that have moved on since
+pub fn load_conversations(
+    data: &DataDir,
+) -> (HashMap<u64, (Conversation, DateTime<Utc>)>, SpeakerNames) {
+    (
+        take(data, "conversations.json"),
+        take(data, "speakers.json"),
+    )
+}
+
+fn save(data: &DataDir, file_name: &str, value: &impl Serialize) {
+    let path = data.path("conversations", file_name);
+    let result = serde_json::to_string(value)
+        .map_err(std::io::Error::from)
+        .and_then(|json| std::fs::write(&path, json));
+
+    if let Err(e) = result {
+        error!("Failed to save conversations to {:?}: {}", path, e);
+    }
+}
+
+fn take<T: DeserializeOwned + Default>(data: &DataDir, file_name: &str) -> T {
+    let path = data.path("conversations", file_name);
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(_) => return T::default(),
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove {:?} after loading it: {}", path, e);
+    }
+    serde_json::from_str(&json).unwrap_or_else(|e| {
+        warn!("Failed to read conversations from {:?}: {}", path, e);
+        T::default()
+    })
+}
+
+impl Conversation {
+    pub fn new(system_prompt: impl Into<String>) -> Self {
+        Conversation {
+            history: vec![Turn::system(system_prompt)],
+        }
+    }
+
+    // Removes everything a speaker said together with the replies to it, returns how many messages they had
+    pub fn remove_speaker(&mut self, speaker: &str) -> usize {
+        let mut removed = 0;
+        let mut replying_to_speaker = false;
+        self.history.retain(|turn| {
+            let keep = match turn.role {
+                Role::User => {
+                    replying_to_speaker = turn.name.as_deref() == Some(speaker);
+                    !replying_to_speaker
+                }
+                Role::Assistant => !replying_to_speaker,
+                _ => true,
+            };
+            if turn.role == Role::User && !keep {
+                removed += 1;
+            }
+            keep
+        });
+        removed
+    }
+}
+
+// The API only accepts names made of letters, digits, _ and -, up to 64 characters
+const MAX_NAME_CHARS: usize = 64;
+// A channel's names outlive its conversation for this long, so undone resets and restarts still know everyone
+const SPEAKER_MEMORY_HOURS: i64 = 24;
+
+// Maps Discord users to the names they have in each channel's conversation.
+// A user keeps the same name in a channel while it is in use, and no two users in a channel share one.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SpeakerNames {
+    #[serde(default)]
+    channels: HashMap<u64, ChannelSpeakers>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChannelSpeakers {
+    names: HashMap<u64, String>,
+    used: DateTime<Utc>,
+}
+
+impl SpeakerNames {
+    pub fn name(&mut self, channel_id: u64, user_id: u64, display_name: &str) -> String {
+        self.name_at(channel_id, user_id, display_name, Utc::now())
+    }
+
+    fn name_at(
+        &mut self,
+        channel_id: u64,
+        user_id: u64,
+        display_name: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        // Names are only needed while a channel is talking to the bot, the others are let go of
+        self.channels.retain(|id, channel| {
+            *id == channel_id || now - channel.used <= Duration::hours(SPEAKER_MEMORY_HOURS)
+        });
+        let channel = self
+            .channels
+            .entry(channel_id)
+            .or_insert_with(|| ChannelSpeakers {
+                names: HashMap::new(),
+                used: now,
+            });
+        channel.used = now;
+        if let Some(name) = channel.names.get(&user_id) {
+            return name.clone();
+        }
+
+        let base = sanitize_name(display_name);
+        let mut name = base.clone();
+        let mut suffix = 2;
+        while channel.names.values().any(|taken| *taken == name) {
+            let suffix_text = format!("_{}", suffix);
+            name = base
+                .chars()
+                .take(MAX_NAME_CHARS - suffix_text.len())
+                .collect::<String>()
+                + &suffix_text;
+            suffix += 1;
+        }
+
+        channel.names.insert(user_id, name.clone());
+        name
+    }
+
+    // Takes the user's name in the channel away, a later message gives them a fresh one
+    pub fn remove(&mut self, channel_id: u64, user_id: u64) -> Option<String> {
+        self.channels.get_mut(&channel_id)?.names.remove(&user_id)
+    }
+
+    pub fn in_channel(&self, channel_id: u64) -> impl Iterator<Item = &String> {
+        self.channels
+            .get(&channel_id)
+            .into_iter()
+            .flat_map(|channel| channel.names.values())
+    }
+}
+
+fn sanitize_name(display_name: &str) -> String {
+    let name: String = display_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_CHARS)
+        .collect();
+
+    if name.trim_matches('_').is_empty() {
+        "user".to_string()
+    } else {
+        name
+    }
+}
+
+// Removes a speaker label the model put in front of its reply, like "Bot:" or "**Alice**:".
+// Only labels naming the bot, a generic role or a known speaker are removed, so replies such as "Note: ..." are kept.
+pub fn strip_speaker_prefix<'a>(
+    response: &str,
+    names: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let names: Vec<String> = names
+        .into_iter()
+        .chain(["assistant", "bot", "ai", "you"])
+        .map(normalize_label)
+        .collect();
+
+    let mut response = response.trim_start();
+    while let Some((label, rest)) = response.split_once(':') {
+        // "**Bot:**" leaves the closing asterisks of the label behind
+        let rest = if label.trim_start().starts_with("**") {
+            rest.trim_start_matches('*')
+        } else {
+            rest
+        };
+        let label = normalize_label(
+            label
+                .trim()
+                .trim_matches(|c: char| matches!(c, '*' | '_' | '[' | ']' | '<' | '>' | '"')),
+        );
+        if label.is_empty() || !names.contains(&label) {
+            break;
+        }
+        response = rest.trim_start();
+    }
+
+    response.to_string()
+}
+
+// Speaker names use _ where the display name had spaces, the model may write either
+fn normalize_label(label: &str) -> String {
+    label.trim().to_lowercase().replace('_', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_a_speaker_also_removes_the_replies_to_them() {
+        let mut conversation = Conversation::new("Be nice.");
+        conversation.history.extend([
+            Turn::user("alice", "hi"),
+            Turn::assistant("hello alice"),
+            Turn::user("bob", "hey"),
+            Turn::assistant("hello bob"),
+            Turn::user("alice", "bye"),
+            Turn::assistant("bye alice"),
+        ]);
+
+        assert_eq!(conversation.remove_speaker("alice"), 2);
+        let contents: Vec<&str> = conversation
+            .history
+            .iter()
+            .map(|turn| turn.content.as_str())
+            .collect();
+        assert_eq!(contents, ["Be nice.", "hey", "hello bob"]);
+    }
+
+    #[test]
+    fn snapshots_keep_speaker_names_and_are_only_loaded_once() {
+        let data = DataDir::temporary();
+        let mut speakers = SpeakerNames::default();
+        speakers.name(7301, 1, "Alice");
+        speakers.name(7301, 2, "Alice");
+        let conversations = HashMap::from([(7301, (Conversation::new("Be nice."), Utc::now()))]);
+
+        save_conversations(&data, &conversations, &speakers);
+        let (loaded, mut speakers) = load_conversations(&data);
+        assert!(loaded.contains_key(&7301));
+        assert_eq!(speakers.name(7301, 2, "someone else"), "Alice_2");
+        assert_eq!(speakers.name(7301, 3, "Alice"), "Alice_3");
+
+        let (loaded, speakers) = load_conversations(&data);
+        assert!(loaded.is_empty());
+        assert_eq!(speakers.in_channel(7301).count(), 0);
+    }
+
+    #[test]
+    fn display_names_are_turned_into_valid_names() {
+        assert_eq!(sanitize_name("Alice"), "Alice");
+        assert_eq!(sanitize_name("Jean-Luc Picard!"), "Jean-Luc_Picard_");
+        assert_eq!(sanitize_name("Zoë"), "Zo_");
+        assert_eq!(sanitize_name("🦀🦀"), "user");
+        assert_eq!(sanitize_name(""), "user");
+        assert_eq!(sanitize_name(&"a".repeat(100)).len(), MAX_NAME_CHARS);
+    }
+
+    #[test]
+    fn users_in_a_channel_never_share_a_name() {
+        let mut speakers = SpeakerNames::default();
+        assert_eq!(speakers.name(1, 10, "Zoë"), "Zo_");
+        assert_eq!(speakers.name(1, 11, "Zoé"), "Zo__2");
+        assert_eq!(speakers.name(1, 12, "Zo?"), "Zo__3");
+        // Users keep their name when their display name changes
+        assert_eq!(speakers.name(1, 10, "Bob"), "Zo_");
+        // Other channels have their own names
+        assert_eq!(speakers.name(2, 11, "Zoé"), "Zo_");
+
+        // Suffixes still fit within the length limit
+        let long = "a".repeat(100);
+        speakers.name(1, 20, &long);
+        let name = speakers.name(1, 21, &long);
+        assert_eq!(name.len(), MAX_NAME_CHARS);
+        assert!(name.ends_with("a_2"));
+    }
+
+    #[test]
+    fn names_of_channels_no_longer_in_use_are_dropped() {
+        let mut speakers = SpeakerNames::default();
+        let long_ago = Utc::now() - Duration::hours(SPEAKER_MEMORY_HOURS + 1);
+        speakers.name_at(1, 10, "Alice", long_ago);
+        speakers.name_at(2, 10, "Alice", long_ago);
+
+        // A channel in use again keeps its names
+        speakers.name(1, 11, "Bob");
+        assert!(speakers.in_channel(1).any(|name| name == "Alice"));
+        assert_eq!(speakers.in_channel(2).count(), 0);
+        assert_eq!(speakers.in_channel(1).count(), 2);
+    }
+
+    #[test]
+    fn only_known_speaker_labels_are_stripped() {
+        let names = ["Alice", "Jean-Luc_Picard"];
+        assert_eq!(strip_speaker_prefix("Bot: hi there", names), "hi there");
+        assert_eq!(strip_speaker_prefix("**Assistant:** hi", names), "hi");
+        assert_eq!(strip_speaker_prefix("**Alice**: hi", names), "hi");
+        assert_eq!(
+            strip_speaker_prefix("[Jean-Luc Picard]: engage", names),
+            "engage"
+        );
+        assert_eq!(strip_speaker_prefix("  bot: you: hello", names), "hello");
+        assert_eq!(
+            strip_speaker_prefix("Note: this stays", names),
+            "Note: this stays"
+        );
+        assert_eq!(
+            strip_speaker_prefix("Bob: not a speaker", names),
+            "Bob: not a speaker"
+        );
+        assert_eq!(
+            strip_speaker_prefix("no label at all", names),
+            "no label at all"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use chatgpt::err::Error;
+use chatgpt::prelude::*;
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::sync::Arc;
+use tracing::info;
+
+// Turns text into a fixed-size vector so that similar texts end up close together
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>>;
+}
+
+// Picks the embedding provider from EMBEDDING_PROVIDER: "hashing" (default), "ollama" or "openai"
+pub fn embedder_from_env() -> Arc<dyn Embedder> {
+    let provider = std::env::var("EMBEDDING_PROVIDER").unwrap_or_else(|_| "hashing".to_string());
+
+    match provider.to_lowercase().as_str() {
+        "openai" => {
+            let api_key =
+                std::env::var("OPENAI_API_KEY").expect("Expected an api key in the environment");
+            let model = std::env::var("EMBEDDING_MODEL")
+                .unwrap_or_else(|_| "text-embedding-ada-002".to_string());
+            info!("Using OpenAI embeddings with model: {}", model);
+            Arc::new(OpenAIEmbedder::new(api_key, model))
+        }
+        "ollama" => {
+            let url = std::env::var("OLLAMA_URL")
+                .unwrap_or_else(|_| "http://localhost:11434".to_string());
+            let model =
+                std::env::var("EMBEDDING_MODEL").unwrap_or_else(|_| "nomic-embed-text".to_string());
+            info!(
+                "Using local Ollama embeddings at {} with model: {}",
+                url, model
+            );
+            Arc::new(OllamaEmbedder::new(url, model))
+        }
+        _ => {
+            info!("Using hashing embeddings");
+            Arc::new(HashingEmbedder::new(512))
+        }
+    }
+}
+
+// Deterministic bag-of-words embedding using the hashing trick.
+// Needs no model or network, which makes it the default and the one to use in tests.
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        HashingEmbedder { dimensions }
+    }
+
+    pub fn embed_sync(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+
+        let words: Vec<String> = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+
+        let bigrams = words
+            .windows(2)
+            .map(|pair| format!("{} {}", pair[0], pair[1]));
+
+        for feature in words.iter().cloned().chain(bigrams) {
+            let hash = fnv1a(feature.as_bytes());
+            let index = (hash % self.dimensions as u64) as usize;
+            // Use one bit of the hash as a sign so that collisions tend to cancel out
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[index] += sign;
+        }
+
+        normalize(&mut vector);
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        Ok(self.embed_sync(text))
+    }
+}
+
+// Embeddings from a model served locally by Ollama, which runs fine on a CPU
+pub struct OllamaEmbedder {
+    http: reqwest::Client,
+    url: String,
+    model: String,
+}
+
+impl OllamaEmbedder {
+    pub fn new(url: String, model: String) -> Self {
+        OllamaEmbedder {
+            http: reqwest::Client::new(),
+            url,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let response: Value = self
+            .http
+            .post(format!("{}/api/embeddings", self.url.trim_end_matches('/')))
+            .json(&json!({ "model": self.model, "prompt": text }))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        parse_vector(&response["embedding"])
+    }
+}
+
+pub struct OpenAIEmbedder {
+    http: reqwest::Client,
+    api_key: String,
+    model: String,
+}
+
+impl OpenAIEmbedder {
+    pub fn new(api_key: String, model: String) -> Self {
+        OpenAIEmbedder {
+            http: reqwest::Client::new(),
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAIEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let response: Value = self
+            .http
+            .post("https://api.openai.com/v1/embeddings")
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "model": self.model, "input": text }))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::BackendError {
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+                error_type: error["type"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+
+        parse_vector(&response["data"][0]["embedding"])
+    }
+}
+
+fn parse_vector(value: &Value) -> Result<Vec<f32>> {
+    let values = value
+        .as_array()
+        .ok_or_else(|| Error::ParsingError("embedding response has no vector".to_string()))?;
+
+    let mut vector: Vec<f32> = values
+        .iter()
+        .filter_map(|value| value.as_f64())
+        .map(|value| value as f32)
+        .collect();
+
+    normalize(&mut vector);
+    Ok(vector)
+}
+
+fn normalize(vector: &mut [f32]) {
+    let length = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if length > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= length);
+    }
+}
+
+// Vectors from different embedders, or the same one with another model, can't be compared
+#[derive(Debug, PartialEq)]
+pub struct DimensionMismatch(pub usize, pub usize);
+
+impl std::fmt::Display for DimensionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "embeddings of {} and {} dimensions can't be compared",
+            self.0, self.1
+        )
+    }
+}
+
+// Vectors are normalized when they are created, so the dot product is the cosine similarity
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> std::result::Result<f32, DimensionMismatch> {
+    if a.len() != b.len() {
+        return Err(DimensionMismatch(a.len(), b.len()));
+    }
+    Ok(a.iter().zip(b).map(|(a, b)| a * b).sum())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similar_texts_score_higher() {
+        let embedder = HashingEmbedder::new(512);
+        let pizza = embedder.embed_sync("my favourite pizza has mushrooms on it");
+        let similar = embedder.embed_sync("pizza with mushrooms is my favourite");
+        let unrelated = embedder.embed_sync("the train to the airport was late");
+
+        assert!((cosine_similarity(&pizza, &pizza).unwrap() - 1.0).abs() < 1e-5);
+        assert!(
+            cosine_similarity(&pizza, &similar).unwrap()
+                > cosine_similarity(&pizza, &unrelated).unwrap()
+        );
+    }
+
+    #[test]
+    fn vectors_of_different_dimensions_are_an_error() {
+        let short = HashingEmbedder::new(256).embed_sync("hello there");
+        let long = HashingEmbedder::new(512).embed_sync("hello there");
+
+        assert_eq!(
+            cosine_similarity(&short, &long),
+            Err(DimensionMismatch(256, 512))
+        );
+    }
+}
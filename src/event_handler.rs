@@ -1,23 +1,67 @@
 use chrono::Duration;
 use chrono::Utc;
 use serenity::async_trait;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
 use serenity::client::Context;
 use serenity::client::EventHandler;
+use serenity::gateway::ConnectionStage;
 use serenity::model::prelude::*;
 use std::sync::Arc;
+use tracing::{debug, info, warn};
 
+use crate::commands::parse_command;
 use crate::handler::QueuedMessage;
+use crate::logging;
+use crate::message_queue::Pushed;
+use crate::metrics::METRICS;
+use crate::permissions::member_roles;
+
+// Sent to the author of a message turned away because the queue is full
+const QUEUE_FULL: &str = "I'm answering a lot of messages right now, please try again in a moment.";
 
 // Implement EventHandler trait for the Handler struct
 #[async_trait]
 impl EventHandler for crate::handler::Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("{} is connected!", ready.user.name);
-        let handler_clone = Arc::new(self.clone());
-        let ctx_clone = ctx.clone();
-        tokio::spawn(async move {
-            handler_clone.queue_handler(ctx_clone).await;
-        });
+        info!("{} is connected!", ready.user.name);
+        let _ = self.bot_name.set(ready.user.name.clone());
+        let _ = self.http.set(ctx.http.clone());
+        self.health.set_gateway(true);
+        Arc::new(self.clone()).start_worker(ctx);
+    }
+
+    async fn resume(&self, _ctx: Context, _: ResumedEvent) {
+        self.health.set_gateway(true);
+    }
+
+    // Readiness follows the gateway connection as serenity drops and re-establishes it
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        info!("Gateway connection {} -> {}", event.old, event.new);
+        self.health.set_gateway(event.new == ConnectionStage::Connected);
+    }
+
+    // Keeps the cached category, name and NSFW flag of edited channels up to date
+    async fn channel_update(&self, _ctx: Context, channel: Channel) {
+        if let Channel::Guild(channel) = channel {
+            self.update_channel(&channel).await;
+        }
+    }
+
+    // Admin checks use a cached copy of the guild's owner and roles, dropped whenever either may have changed
+    async fn guild_update(&self, _ctx: Context, guild: PartialGuild) {
+        self.forget_guild_admins(guild.id).await;
+    }
+
+    async fn guild_role_create(&self, _ctx: Context, guild_id: GuildId, _role: Role) {
+        self.forget_guild_admins(guild_id).await;
+    }
+
+    async fn guild_role_update(&self, _ctx: Context, guild_id: GuildId, _role: Role) {
+        self.forget_guild_admins(guild_id).await;
+    }
+
+    async fn guild_role_delete(&self, _ctx: Context, guild_id: GuildId, _role_id: RoleId) {
+        self.forget_guild_admins(guild_id).await;
     }
 
     // This function will be called when a message is received
@@ -27,11 +71,28 @@ impl EventHandler for crate::handler::Handler {
         if msg.author.id == bot_user.id {
             return;
         }
+        METRICS.messages_received.inc();
+
+        if self.health.shutting_down() {
+            METRICS.dropped("shutdown");
+            return;
+        }
 
-        println!("\nRecived A Message: {}", msg.content);
+        debug!(
+            guild = msg.guild_id.map(|guild_id| guild_id.0),
+            channel = msg.channel_id.0,
+            user = msg.author.id.0,
+            "Received a message: {}",
+            logging::content(&msg.content)
+        );
 
         let channel_id = msg.channel_id.0;
-        let should_respond = {
+
+        // Commands are answered directly and never reach the conversation queue
+        let command = parse_command(&msg.content, &bot_user.name);
+
+        // Check if the message contains the bot's name or was sent within 30 seconds of the last conversation message in the channel
+        let should_respond = command.is_none() && {
             let conversations = self.conversations.lock().await;
             if let Some((_, last_message)) = conversations.get(&channel_id) {
                 msg.content
@@ -45,16 +106,94 @@ impl EventHandler for crate::handler::Handler {
             }
         };
 
-        // Check if the message contains the bot's name or was sent within 1 minute of the last conversation message in the channel
-        if should_respond {
-            let queued_message = QueuedMessage {
-                channel_id: msg.channel_id.0,
-                author_name: msg.author.name.clone(),
-                content: msg.content.clone(),
+        // Every message counts towards the mood of the channel, not only the ones the bot answers.
+        // Those it answers are scored by the queue worker, so only they can take a request to the model.
+        if command.is_none() && !should_respond {
+            if let Some(score) = self.sentiment.analyze_without_fallback(&msg.content).await {
+                self.moods
+                    .record(
+                        msg.guild_id.map(|guild_id| guild_id.0),
+                        channel_id,
+                        msg.author.id.0,
+                        score,
+                    )
+                    .await;
+            }
+            return;
+        }
+
+        // Access rules, blocks and mutes decide who the bot listens to and where, admins are never locked out.
+        // Only commands and messages the bot would answer are checked, the rest is left alone anyway.
+        let roles = member_roles(&msg);
+        if let Some(guild_id) = msg.guild_id {
+            let category_id = self.channel_category(&ctx, msg.channel_id).await;
+            let settings = self.settings.get(guild_id.0).await;
+            let ignored = match settings.access.check(msg.channel_id.0, category_id, &roles) {
+                Err(reason) => Err(("access", reason)),
+                Ok(()) if settings.blocked_users.contains(&msg.author.id.0) => {
+                    Err(("blocked", "the user is blocked"))
+                }
+                Ok(()) if self.abuse.muted_until(guild_id.0, msg.author.id.0).await.is_some() => {
+                    Err(("muted", "the user is muted"))
+                }
+                Ok(()) => Ok(()),
             };
+            if let Err((dropped, reason)) = ignored {
+                if !self.is_admin(&ctx, &msg).await {
+                    METRICS.dropped(dropped);
+                    info!(
+                        guild = guild_id.0,
+                        channel = msg.channel_id.0,
+                        user = msg.author.id.0,
+                        "Ignoring message: {}",
+                        reason
+                    );
+                    return;
+                }
+            }
+        }
+
+        if let Some(command) = command {
+            if !self.throttle(&ctx, &msg).await {
+                self.run_command(&ctx, &msg, command).await;
+            }
+            return;
+        }
+
+        let queued_message = QueuedMessage {
+            message_id: msg.id.0,
+            guild_id: msg.guild_id.map(|guild_id| guild_id.0),
+            channel_id: msg.channel_id.0,
+            category_id: self.channel_category(&ctx, msg.channel_id).await,
+            channel_name: self.channel_name(&ctx, msg.channel_id).await,
+            guild_name: match msg.guild_id {
+                Some(guild_id) => self.guild_name(&ctx, guild_id).await,
+                None => None,
+            },
+            nsfw: self.channel_nsfw(&ctx, msg.channel_id).await,
+            author_id: msg.author.id.0,
+            author_roles: roles,
+            author_name: msg.author.name.clone(),
+            content: msg.content.clone(),
+        };
 
-            if let Err(e) = self.sender.send(queued_message).await {
-                eprintln!("Failed to send message to the queue: {}", e);
+        if self.throttle(&ctx, &msg).await {
+            return;
+        }
+        match self.queue.push(queued_message).await {
+            Pushed::Queued => {}
+            Pushed::Duplicate => {
+                debug!("Ignoring message {}, it was already queued", msg.id);
+                METRICS.dropped("duplicate");
+            }
+            Pushed::DroppedOldest(dropped) => {
+                warn!("The queue is full, dropped message {}", dropped.message_id);
+                METRICS.dropped("overflow");
+            }
+            Pushed::Rejected => {
+                warn!("The queue is full, rejected message {}", msg.id);
+                METRICS.dropped("overflow");
+                self.say(&ctx, msg.channel_id, QUEUE_FULL).await;
             }
         }
     }
@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use whatlang::Lang;
+
+use crate::access_control::AccessRules;
+use crate::injection::InjectionAction;
+use crate::moderation::ModerationSettings;
+use crate::preset_selection::PresetAccess;
+use crate::storage::{write_off_task, DataDir};
+use crate::tone::TonePolicy;
+
+// A persona is either one of the built-in presets, by name, or a prompt written by an admin
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Persona {
+    Preset(String),
+    Custom(String),
+}
+
+impl std::fmt::Display for Persona {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Persona::Preset(name) => write!(f, "{}", name),
+            Persona::Custom(_) => write!(f, "custom prompt"),
+        }
+    }
+}
+
+// Settings for a single channel, or for every channel in a category
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ChannelSettings {
+    #[serde(default)]
+    pub persona: Option<Persona>,
+    // Unset in a channel means its category decides
+    #[serde(default)]
+    pub allow_persona_switch: Option<bool>,
+    #[serde(default)]
+    pub acknowledge_persona_switch: Option<bool>,
+    // ISO 639-3 code of the language replies are always written in, instead of the one detected from the message
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct GuildSettings {
+    // Keyed by channel or category id
+    #[serde(default)]
+    pub channels: HashMap<u64, ChannelSettings>,
+    #[serde(default)]
+    pub tone: TonePolicy,
+    // What happens to messages that look like prompt injection attempts
+    #[serde(default)]
+    pub injection: InjectionAction,
+    #[serde(default)]
+    pub moderation: ModerationSettings,
+    // Restricted presets admins allowed outside NSFW channels
+    #[serde(default)]
+    pub allowed_presets: Vec<String>,
+    #[serde(default)]
+    pub access: AccessRules,
+    // Users the bot ignores, set by admins
+    #[serde(default)]
+    pub blocked_users: Vec<u64>,
+    // Where mutes and blocks are reported
+    #[serde(default)]
+    pub mod_log_channel: Option<u64>,
+}
+
+impl GuildSettings {
+    // The persona pinned for a channel, falling back to the one pinned for its category
+    pub fn pinned_persona(&self, channel_id: u64, category_id: Option<u64>) -> Option<&Persona> {
+        self.channels
+            .get(&channel_id)
+            .and_then(|settings| settings.persona.as_ref())
+            .or_else(|| {
+                category_id
+                    .and_then(|category_id| self.channels.get(&category_id))
+                    .and_then(|settings| settings.persona.as_ref())
+            })
+    }
+
+    // The reply language set for a channel, falling back to the one set for its category
+    pub fn language(&self, channel_id: u64, category_id: Option<u64>) -> Option<Lang> {
+        [Some(channel_id), category_id]
+            .iter()
+            .flatten()
+            .filter_map(|id| self.channels.get(id))
+            .find_map(|settings| settings.language.as_deref())
+            .and_then(Lang::from_code)
+    }
+
+    // Without roles, as for persona changes made by admins, the per-preset role rules are not applied
+    pub fn preset_access(&self, nsfw: bool, roles: Option<&[u64]>) -> PresetAccess {
+        let denied = match roles {
+            Some(roles) => self
+                .access
+                .presets
+                .keys()
+                .filter(|preset| !self.access.allows_preset(preset, roles))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        PresetAccess {
+            nsfw,
+            allowlist: self.allowed_presets.clone(),
+            denied,
+        }
+    }
+
+    pub fn allows_persona_switch(&self, channel_id: u64, category_id: Option<u64>) -> bool {
+        self.toggle(channel_id, category_id, |settings| {
+            settings.allow_persona_switch
+        })
+    }
+
+    pub fn acknowledges_persona_switch(&self, channel_id: u64, category_id: Option<u64>) -> bool {
+        self.toggle(channel_id, category_id, |settings| {
+            settings.acknowledge_persona_switch
+        })
+    }
+
+    // A channel's own setting wins over its category's, off when neither is set
+    fn toggle(
+        &self,
+        channel_id: u64,
+        category_id: Option<u64>,
+        setting: impl Fn(&ChannelSettings) -> Option<bool>,
+    ) -> bool {
+        [Some(channel_id), category_id]
+            .iter()
+            .flatten()
+            .filter_map(|id| self.channels.get(id))
+            .find_map(setting)
+            .unwrap_or(false)
+    }
+}
+
+// Per-guild settings managed by admins, persisted as one JSON file per guild
+pub struct SettingsStore {
+    guilds: Mutex<HashMap<u64, GuildSettings>>,
+    data: DataDir,
+}
+
+impl SettingsStore {
+    pub fn new(data: DataDir) -> Self {
+        SettingsStore {
+            guilds: Mutex::new(HashMap::new()),
+            data,
+        }
+    }
+
+    pub async fn get(&self, guild_id: u64) -> GuildSettings {
+        let mut guilds = self.guilds.lock().await;
+        load_guild(&self.data, &mut guilds, guild_id).clone()
+    }
+
+    // Applies a change to a guild's settings and saves them
+    pub async fn update<R>(
+        &self,
+        guild_id: u64,
+        change: impl FnOnce(&mut GuildSettings) -> R,
+    ) -> R {
+        let mut guilds = self.guilds.lock().await;
+        let settings = load_guild(&self.data, &mut guilds, guild_id);
+        let result = change(settings);
+
+        let path = self.data.path("settings", &format!("{}.json", guild_id));
+        let saved = match serde_json::to_string_pretty(settings) {
+            Ok(json) => {
+                let path = path.clone();
+                write_off_task(move || std::fs::write(path, json)).await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = saved {
+            error!("Failed to save guild settings to {:?}: {}", path, e);
+        }
+
+        result
+    }
+}
+
+fn load_guild<'a>(
+    data: &DataDir,
+    guilds: &'a mut HashMap<u64, GuildSettings>,
+    guild_id: u64,
+) -> &'a mut GuildSettings {
+    guilds.entry(guild_id).or_insert_with(|| {
+        let path = data.path("settings", &format!("{}.json", guild_id));
+        match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Failed to read guild settings from {:?}: {}", path, e);
+                GuildSettings::default()
+            }),
+            Err(_) => GuildSettings::default(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: u64 = 1;
+    const CATEGORY: u64 = 2;
+
+    fn settings(channel: Option<bool>, category: Option<bool>) -> GuildSettings {
+        let mut settings = GuildSettings::default();
+        for (id, allow) in [(CHANNEL, channel), (CATEGORY, category)] {
+            settings.channels.insert(
+                id,
+                ChannelSettings {
+                    allow_persona_switch: allow,
+                    ..Default::default()
+                },
+            );
+        }
+        settings
+    }
+
+    #[test]
+    fn the_channel_setting_wins_over_the_category() {
+        let allows = |channel, category| {
+            settings(channel, category).allows_persona_switch(CHANNEL, Some(CATEGORY))
+        };
+
+        assert!(!allows(Some(false), Some(true)));
+        assert!(allows(Some(true), Some(false)));
+        assert!(allows(None, Some(true)));
+        assert!(!allows(None, None));
+        // Channels outside a category only have their own setting
+        assert!(!settings(None, Some(true)).allows_persona_switch(CHANNEL, None));
+    }
+}
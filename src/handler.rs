@@ -1,83 +1,1033 @@
 use chatgpt::prelude::*;
 
 use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
 use serenity::prelude::*;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{sync::mpsc, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use serenity::model::prelude::ChannelId;
+use chatgpt::types::{ChatMessage, Role};
+use serenity::model::prelude::{Channel, ChannelId, GuildChannel, GuildId};
 
-use crate::sentiment_analysis::get_preset_based_on_sentiment;
+use crate::abuse::{describe_duration, AbuseTracker, Mute};
+use crate::chat_client::ChatBackend;
+use crate::conversation::{
+    load_conversations, save_conversations, strip_speaker_prefix, Conversation, SpeakerNames, Turn,
+};
+use crate::guild_settings::{Persona, SettingsStore};
+use crate::health::{Health, WorkerStatus};
+use crate::injection::{quote_user_content, InjectionAction, InjectionDetector, REFUSAL, WARNING};
+use crate::knowledge_base::{append_citations, knowledge_prompt, KnowledgeBase};
+use crate::language::detect_language;
+use crate::logging;
+use crate::message_queue::MessageQueue;
+use crate::metrics::METRICS;
+use crate::moderation::{
+    self, ModerationAction, ModerationPipeline, Outcome, BLOCKED_MESSAGE, WITHHELD_REPLY,
+};
+use crate::mood::MoodTracker;
+use crate::permissions::GuildAdmins;
+use crate::preset_router::PresetRouter;
+use crate::preset_selection::PresetAccess;
+use crate::prompt::{PromptSection, SystemPrompt};
+use crate::semantic_memory::SemanticMemory;
+use crate::sentiment_analysis::{get_preset_based_on_sentiment, MultilingualSentiment};
+use crate::storage::DataDir;
+use crate::template::TemplateVariables;
+use crate::tone::TonePolicy;
+use crate::user_memory::UserMemory;
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct QueuedMessage {
+    // Discord id of the message, the queue ignores ids it already has
+    pub message_id: u64,
+    pub guild_id: Option<u64>,
     pub channel_id: u64,
+    pub category_id: Option<u64>,
+    pub channel_name: Option<String>,
+    pub guild_name: Option<String>,
+    // Restricted presets can be used in NSFW channels
+    pub nsfw: bool,
+    pub author_id: u64,
+    // Role ids of the author in the guild, including the @everyone role
+    pub author_roles: Vec<u64>,
     pub author_name: String,
     pub content: String,
 }
 
+// How often the watchdog checks on the queue worker
+const WATCHDOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How long a reset can be undone for
+pub const UNDO_WINDOW_MINUTES: i64 = 2;
+
+// What a reset clears
+pub enum ResetScope {
+    // A user's messages, and the replies to them, in one channel's conversation
+    Mine { channel_id: u64, speaker: String },
+    // The whole conversation of each of these channels
+    Channels(Vec<u64>),
+}
+
+// What the last reset in a channel cleared, kept for a while so it can be undone
+pub struct ResetUndo {
+    user_id: u64,
+    at: chrono::DateTime<Utc>,
+    conversations: Vec<ResetConversation>,
+}
+
+// A conversation as it was before a reset, and the turns the reset left of it
+struct ResetConversation {
+    channel_id: u64,
+    before: ConversationEntry,
+    kept: Vec<Turn>,
+}
+
+// A channel's conversation together with the time of its last message
+pub type ConversationEntry = (Conversation, chrono::DateTime<Utc>);
+
+// Conversations idle for longer than this are started over with a freshly chosen preset
+fn conversation_expired(conversation_entry: &ConversationEntry) -> bool {
+    Utc::now().signed_duration_since(conversation_entry.1) > Duration::minutes(5)
+}
+
 pub struct Handler {
     pub chat_gpt_client: ChatGPT,
-    pub conversations: Arc<Mutex<HashMap<u64, (Conversation, chrono::DateTime<Utc>)>>>,
-    pub sender: mpsc::Sender<QueuedMessage>,
-    pub receiver: Arc<Mutex<mpsc::Receiver<QueuedMessage>>>,
+    pub chat_client: Arc<dyn ChatBackend>,
+    pub speakers: Arc<Mutex<SpeakerNames>>,
+    // Set once the bot is connected, used to remove its name if the model starts a reply with it
+    pub bot_name: Arc<OnceLock<String>>,
+    // Set once the bot is connected, so messages can be posted outside of event handlers
+    pub http: Arc<OnceLock<Arc<serenity::http::Http>>>,
+    pub conversations: Arc<Mutex<HashMap<u64, ConversationEntry>>>,
+    pub queue: Arc<MessageQueue>,
+    pub memory: Arc<SemanticMemory>,
+    pub knowledge: Arc<KnowledgeBase>,
+    pub user_memory: Arc<UserMemory>,
+    pub settings: Arc<SettingsStore>,
+    // Personas picked by users in channels that allow switching, keyed by channel id
+    pub chosen_personas: Arc<Mutex<HashMap<u64, Persona>>>,
+    // Category of each channel seen so far, to avoid fetching the channel for every message
+    pub channel_categories: Arc<Mutex<HashMap<u64, Option<u64>>>>,
+    // Owner and admin roles of the guilds seen so far, for permission checks
+    pub guild_admins: Arc<Mutex<HashMap<u64, GuildAdmins>>>,
+    // Names of the channels and guilds seen so far, for prompt templates
+    pub names: Arc<Mutex<HashMap<u64, String>>>,
+    // Channels flagged as NSFW, looked up together with the category
+    pub nsfw_channels: Arc<Mutex<HashSet<u64>>>,
+    pub router: Arc<PresetRouter>,
+    pub moods: Arc<MoodTracker>,
+    pub sentiment: Arc<MultilingualSentiment>,
+    pub injection: Arc<InjectionDetector>,
+    pub moderation: Arc<ModerationPipeline>,
+    pub abuse: Arc<AbuseTracker>,
+    pub health: Arc<Health>,
+    pub data: DataDir,
+    // The last reset asked for in each channel, keyed by channel id
+    pub reset_undo: Arc<Mutex<HashMap<u64, ResetUndo>>>,
 }
 
 impl Clone for Handler {
     fn clone(&self) -> Self {
         Self {
             chat_gpt_client: self.chat_gpt_client.clone(),
+            chat_client: self.chat_client.clone(),
+            speakers: self.speakers.clone(),
+            bot_name: self.bot_name.clone(),
+            http: self.http.clone(),
             conversations: self.conversations.clone(),
-            sender: self.sender.clone(),
-            receiver: self.receiver.clone(),
+            queue: self.queue.clone(),
+            memory: self.memory.clone(),
+            knowledge: self.knowledge.clone(),
+            user_memory: self.user_memory.clone(),
+            settings: self.settings.clone(),
+            chosen_personas: self.chosen_personas.clone(),
+            channel_categories: self.channel_categories.clone(),
+            guild_admins: self.guild_admins.clone(),
+            names: self.names.clone(),
+            nsfw_channels: self.nsfw_channels.clone(),
+            router: self.router.clone(),
+            moods: self.moods.clone(),
+            sentiment: self.sentiment.clone(),
+            injection: self.injection.clone(),
+            moderation: self.moderation.clone(),
+            abuse: self.abuse.clone(),
+            health: self.health.clone(),
+            data: self.data.clone(),
+            reset_undo: self.reset_undo.clone(),
+        }
+    }
+}
+
+impl QueuedMessage {
+    // Memories are shared across a guild, direct messages get their own
+    pub fn memory_key(&self) -> u64 {
+        self.guild_id.unwrap_or(self.channel_id)
+    }
+
+    // A message from a test user in channel `guild_id + 1` of the guild, with a fresh message id
+    #[cfg(test)]
+    pub fn test(guild_id: u64, content: &str) -> Self {
+        QueuedMessage {
+            message_id: rand::random(),
+            guild_id: Some(guild_id),
+            channel_id: guild_id + 1,
+            category_id: None,
+            channel_name: Some("general".to_string()),
+            guild_name: Some("Test server".to_string()),
+            nsfw: false,
+            author_id: 42,
+            author_roles: Vec::new(),
+            author_name: "Trudy".to_string(),
+            content: content.to_string(),
         }
     }
 }
 
 impl Handler {
-    pub async fn new_chatbot(client: ChatGPT) -> Self {
-        let (sender, receiver) = mpsc::channel(100);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new_chatbot(
+        client: ChatGPT,
+        chat_client: Arc<dyn ChatBackend>,
+        data: DataDir,
+        memory: SemanticMemory,
+        knowledge: KnowledgeBase,
+        router: PresetRouter,
+        sentiment: MultilingualSentiment,
+        injection: InjectionDetector,
+        moderation: ModerationPipeline,
+    ) -> Self {
+        let (conversations, speakers) = load_conversations(&data);
         Handler {
             chat_gpt_client: client,
-            conversations: Arc::new(Mutex::new(HashMap::new())),
-            sender,
-            receiver: Arc::new(Mutex::new(receiver)),
+            health: Arc::new(Health::new(chat_client.clone())),
+            chat_client,
+            speakers: Arc::new(Mutex::new(speakers)),
+            bot_name: Arc::new(OnceLock::new()),
+            http: Arc::new(OnceLock::new()),
+            conversations: Arc::new(Mutex::new(conversations)),
+            queue: Arc::new(MessageQueue::from_env(&data)),
+            memory: Arc::new(memory),
+            knowledge: Arc::new(knowledge),
+            user_memory: Arc::new(UserMemory::new(data.clone())),
+            settings: Arc::new(SettingsStore::new(data.clone())),
+            chosen_personas: Arc::new(Mutex::new(HashMap::new())),
+            channel_categories: Arc::new(Mutex::new(HashMap::new())),
+            guild_admins: Arc::new(Mutex::new(HashMap::new())),
+            names: Arc::new(Mutex::new(HashMap::new())),
+            nsfw_channels: Arc::new(Mutex::new(HashSet::new())),
+            router: Arc::new(router),
+            moods: Arc::new(MoodTracker::default()),
+            sentiment: Arc::new(sentiment),
+            injection: Arc::new(injection),
+            moderation: Arc::new(moderation),
+            abuse: Arc::new(AbuseTracker::default()),
+            reset_undo: Arc::new(Mutex::new(HashMap::new())),
+            data,
+        }
+    }
+
+    // A handler answering through the given backend, with local embeddings, no moderators and keyword routing.
+    // Everything it saves goes to a temporary directory of its own.
+    #[cfg(test)]
+    pub async fn with_backend(backend: Arc<dyn ChatBackend>) -> Self {
+        let data = DataDir::temporary();
+        let client = ChatGPT::new("test").unwrap();
+        let embedder = Arc::new(crate::embeddings::HashingEmbedder::new(512));
+        Handler::new_chatbot(
+            client.clone(),
+            backend,
+            data.clone(),
+            SemanticMemory::new(embedder.clone(), data.clone()),
+            KnowledgeBase::new(embedder.clone(), data),
+            PresetRouter::new(
+                crate::preset_router::RouterMode::Keywords,
+                0.3,
+                embedder,
+                client,
+            ),
+            MultilingualSentiment::new(HashMap::new(), None),
+            InjectionDetector::new(None),
+            ModerationPipeline::default(),
+        )
+        .await
+    }
+
+    // Starts the queue worker, and a watchdog that restarts it whenever it dies or gets stuck.
+    // Discord sends ready again after reconnecting, only the first one starts them.
+    pub fn start_worker(self: Arc<Self>, ctx: Context) {
+        if !self.health.claim_watchdog() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.health.worker_status() {
+                    WorkerStatus::Running => continue,
+                    WorkerStatus::Stopped => {}
+                    status => error!("The queue worker is {:?}, restarting it", status),
+                }
+                let worker = self.clone().queue_handler(ctx.clone());
+                self.restart_worker(worker).await;
+            }
+        });
+    }
+
+    // Replaces the queue worker. The previous one is stopped and what it was answering put back
+    // in the queue before the new one starts, so no message is taken twice.
+    pub async fn restart_worker(&self, worker: impl Future<Output = ()> + Send + 'static) {
+        self.health.abort_worker();
+        self.queue.requeue_in_flight().await;
+        self.health.set_worker(tokio::spawn(worker));
+    }
+
+    // Stops taking new messages, gives the queue up to `timeout` to drain and saves the conversations.
+    // Messages still queued after that are answered once the bot is back.
+    pub async fn shutdown(&self, timeout: std::time::Duration) {
+        self.health.begin_shutdown();
+        info!(
+            "Shutting down, waiting up to {}s for {} queued messages",
+            timeout.as_secs(),
+            self.queue.len().await
+        );
+
+        let deadline = Instant::now() + timeout;
+        while self.queue.len().await > 0 {
+            if Instant::now() >= deadline {
+                warn!(
+                    "Shutdown timed out, {} queued messages will be answered after the restart",
+                    self.queue.len().await
+                );
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
+
+        save_conversations(
+            &self.data,
+            &*self.conversations.lock().await,
+            &*self.speakers.lock().await,
+        );
     }
 
     pub async fn queue_handler(self: Arc<Self>, ctx: Context) {
         loop {
-            if let Some(queued_message) = self.receive_message().await {
-                let response_result = self.chatbot_response(&queued_message).await;
-                let mut conversations = self.conversations.lock().await;
-
-                if queued_message.content.to_lowercase().contains("!reset!") {
-                    println!("\tMessage Contains Reset\n\tPreforming a Full reset");
-                    self.full_reset(&mut conversations, queued_message.channel_id);
-                    continue;
-                }
+            let queued_message = self.queue.next().await;
+
+            // Everything logged while handling the message is tagged with where it came from
+            let span = info_span!(
+                "message",
+                guild = queued_message.guild_id,
+                channel = queued_message.channel_id,
+                user = queued_message.author_id,
+                preset = field::Empty,
+                latency_ms = field::Empty,
+            );
+            self.health.begin_message();
+            self.process_message(&ctx, &queued_message)
+                .instrument(span)
+                .await;
+            self.health.end_message();
+            self.queue.ack(queued_message.message_id).await;
+
+            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        }
+    }
+
+    async fn process_message(&self, ctx: &Context, queued_message: &QueuedMessage) {
+        let started = Instant::now();
+        let response_result = self.chatbot_response(queued_message).await;
+
+        match response_result {
+            Ok(response) => {
+                self.send_response(ctx.http.clone(), queued_message, response)
+                    .await;
+            }
+            Err(e) => self.handle_error(e, queued_message).await,
+        }
+
+        Span::current().record("latency_ms", started.elapsed().as_millis() as u64);
+        info!("Handled message");
+    }
+
+    pub async fn chatbot_response(&self, queued_message: &QueuedMessage) -> Result<String> {
+        let memory_key = queued_message.memory_key();
+
+        let settings = match queued_message.guild_id {
+            Some(guild_id) => self.settings.get(guild_id).await,
+            None => Default::default(),
+        };
+
+        // Flagged messages are blocked or redacted before anything else sees them
+        let outcome = self
+            .moderation
+            .apply(
+                &queued_message.content,
+                settings.moderation.input,
+                &format!("a message from {}", queued_message.author_id),
+            )
+            .await;
+
+        // Every flagged message is a strike against its author, enough of them get the author muted
+        let mut mute_notice = None;
+        if let (false, Some(guild_id)) =
+            (matches!(outcome, Outcome::Allowed), queued_message.guild_id)
+        {
+            let mute = self
+                .strike(
+                    guild_id,
+                    queued_message.author_id,
+                    &queued_message.author_name,
+                    "moderation hits",
+                )
+                .await;
+            mute_notice = mute.map(|mute| {
+                format!(
+                    "{}, you've been muted for {} for repeatedly breaking the rules.",
+                    queued_message.author_name,
+                    describe_duration(mute.duration)
+                )
+            });
+        }
+
+        let mut warnings = Vec::new();
+        let redacted_message;
+        let queued_message = match outcome {
+            Outcome::Allowed => queued_message,
+            Outcome::Blocked => return Ok(with_notice(BLOCKED_MESSAGE.to_string(), mute_notice)),
+            Outcome::Redacted(content) => {
+                redacted_message = QueuedMessage {
+                    content,
+                    ..queued_message.clone()
+                };
+                &redacted_message
+            }
+            Outcome::Warned(categories) => {
+                warnings.extend(categories);
+                queued_message
+            }
+        };
+
+        // Messages trying to override the bot's instructions are refused before anything is looked up or stored
+        let detection = self.injection.detect(&queued_message.content).await;
+        let injection = detection.flagged();
+        if injection {
+            warn!(
+                "Possible prompt injection ({}, score {:.2}, {}): {}",
+                detection.method,
+                detection.score,
+                detection.reasons.join(", "),
+                settings.injection
+            );
+            if settings.injection == InjectionAction::Refuse {
+                return Ok(REFUSAL.to_string());
+            }
+        }
+
+        // Look up related messages from earlier conversations before this one is stored
+        let memories = self
+            .memory
+            .recall(
+                memory_key,
+                queued_message.channel_id,
+                &queued_message.content,
+                Duration::minutes(10),
+            )
+            .await;
+
+        let knowledge = match queued_message.guild_id {
+            Some(guild_id) => {
+                self.knowledge
+                    .retrieve(guild_id, &queued_message.content)
+                    .await
+            }
+            None => Vec::new(),
+        };
+
+        let facts = self
+            .user_memory
+            .facts(memory_key, queued_message.author_id)
+            .await;
+
+        // Everything that only applies to this turn, composed into a single system message
+        let mut context = SystemPrompt::default();
+        if !facts.is_empty() {
+            context.add(
+                PromptSection::Memory,
+                format!(
+                    "{} has asked you to remember the following about them:\n{}",
+                    queued_message.author_name,
+                    quote_user_content(
+                        &facts
+                            .iter()
+                            .map(|fact| format!("- {}", fact.text))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )
+                ),
+            );
+        }
+        if !memories.is_empty() {
+            context.add(PromptSection::Memory, format!(
+                "Things you remember from earlier conversations, use them only if they are relevant:\n{}",
+                quote_user_content(&memories.join("\n"))
+            ));
+        }
+        if !knowledge.is_empty() {
+            context.add(PromptSection::Knowledge, knowledge_prompt(&knowledge));
+        }
+
+        if injection && settings.injection == InjectionAction::Warn {
+            context.add(PromptSection::Rules, WARNING);
+        }
+
+        // Messages the bot answers are scored here rather than on the gateway, in any language
+        let (_, score) = self.sentiment.analyze(&queued_message.content).await;
+        self.moods
+            .record(
+                queued_message.guild_id,
+                queued_message.channel_id,
+                queued_message.author_id,
+                score,
+            )
+            .await;
+
+        // The tone set when the conversation started follows the mood of the channel and the user as it changes
+        if let Some(score) = self
+            .moods
+            .blended(
+                queued_message.guild_id,
+                queued_message.channel_id,
+                queued_message.author_id,
+            )
+            .await
+        {
+            debug!("Current mood score: {:.2}", score);
+            context.add(
+                PromptSection::Tone,
+                format!(
+                    "Adjust your tone to how the conversation is going now: {}",
+                    settings.tone.tone(score)
+                ),
+            );
+        }
+
+        // Reply in the language the channel is set to, otherwise in the one the message is written in
+        let language = settings
+            .language(queued_message.channel_id, queued_message.category_id)
+            .or_else(|| detect_language(&queued_message.content));
+        if let Some(language) = language {
+            context.add(
+                PromptSection::Language,
+                format!(
+                    "Write your reply in {}, whatever language your instructions are in.",
+                    language.eng_name()
+                ),
+            );
+        }
+
+        let variables = TemplateVariables {
+            user: queued_message.author_name.clone(),
+            channel: queued_message.channel_name.clone().unwrap_or_default(),
+            guild: queued_message
+                .guild_name
+                .clone()
+                .unwrap_or_else(|| "direct messages".to_string()),
+            time: current_time(),
+            // Scored in the message's own language, so the tone doesn't stay neutral for everything but English
+            tone: settings.tone.tone(score),
+            memory: facts.iter().map(|fact| fact.text.clone()).collect(),
+        };
+
+        let access =
+            settings.preset_access(queued_message.nsfw, Some(&queued_message.author_roles));
+
+        // Without a pinned or chosen persona, the router picks one whenever a conversation (re)starts
+        let persona = match self
+            .resolve_persona(
+                queued_message.guild_id,
+                queued_message.channel_id,
+                queued_message.category_id,
+            )
+            .await
+        {
+            Some(persona) => Some(persona),
+            None if self.needs_new_conversation(queued_message.channel_id).await => {
+                let decision = self.router.route(&queued_message.content, &access).await;
+                Some(Persona::Preset(decision.preset.name.to_string()))
+            }
+            None => None,
+        };
+        if let Some(persona) = &persona {
+            Span::current().record("preset", field::display(persona));
+        }
+
+        // Each user keeps the same speaker name across the conversation, whatever their display name looks like
+        let speaker = self.speakers.lock().await.name(
+            queued_message.channel_id,
+            queued_message.author_id,
+            &queued_message.author_name,
+        );
+        let message = Turn::user(&speaker, queued_message.content.clone());
+
+        let response = self
+            .chatbot(
+                queued_message.channel_id,
+                message,
+                &context,
+                persona.as_ref(),
+                &access,
+                &variables,
+            )
+            .await?;
 
-                match response_result {
-                    Ok(response) => {
-                        self.send_response(ctx.http.clone(), &queued_message, response)
-                            .await;
+        // The reply is moderated before it is remembered, and the conversation keeps the moderated version
+        let response = match self
+            .moderation
+            .apply(
+                &response,
+                settings.moderation.output,
+                &format!("a reply in channel {}", queued_message.channel_id),
+            )
+            .await
+        {
+            Outcome::Allowed => response,
+            Outcome::Blocked => {
+                self.replace_last_reply(queued_message.channel_id, &response, WITHHELD_REPLY)
+                    .await;
+                WITHHELD_REPLY.to_string()
+            }
+            Outcome::Redacted(redacted) => {
+                self.replace_last_reply(queued_message.channel_id, &response, &redacted)
+                    .await;
+                redacted
+            }
+            Outcome::Warned(categories) => {
+                for category in categories {
+                    if !warnings.contains(&category) {
+                        warnings.push(category);
                     }
-                    Err(e) => self.handle_error(e, &queued_message).await,
                 }
+                response
             }
+        };
 
-            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        self.memory
+            .remember(
+                memory_key,
+                queued_message.channel_id,
+                queued_message.author_id,
+                &queued_message.author_name,
+                &queued_message.content,
+            )
+            .await;
+        self.memory
+            .remember_reply(
+                memory_key,
+                queued_message.channel_id,
+                queued_message.author_id,
+                &response,
+            )
+            .await;
+
+        let response = append_citations(&response, &knowledge);
+        let response = if warnings.is_empty() {
+            response
+        } else {
+            format!("{}\n{}", moderation::warning(&warnings), response)
+        };
+        Ok(with_notice(response, mute_notice))
+    }
+
+    // Counts a strike against a user and reports the mute it leads to, if any, in the mod log
+    pub async fn strike(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        user_name: &str,
+        reason: &str,
+    ) -> Option<Mute> {
+        let mute = self.abuse.strike(guild_id, user_id).await;
+        self.report_strike(guild_id, user_id, user_name, reason, mute.as_ref())
+            .await;
+        mute
+    }
+
+    pub async fn report_strike(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        user_name: &str,
+        reason: &str,
+        mute: Option<&Mute>,
+    ) {
+        match mute {
+            Some(mute) => {
+                self.mod_log(
+                    guild_id,
+                    &format!(
+                        "Muted {} ({}) for {} after repeated {}, mute #{} in a row, until {}",
+                        user_name,
+                        user_id,
+                        describe_duration(mute.duration),
+                        reason,
+                        mute.count,
+                        mute.until.format("%Y-%m-%d %H:%M UTC")
+                    ),
+                )
+                .await
+            }
+            None => info!(guild = guild_id, user = user_id, "Strike for {}", reason),
+        }
+    }
+
+    // Counts a message to the bot towards spam detection, returns whether it is spam and should be dropped
+    pub async fn throttle(&self, ctx: &Context, msg: &serenity::model::channel::Message) -> bool {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) => guild_id.0,
+            None => return false,
+        };
+
+        let (spam, mute) = self.abuse.record_message(guild_id, msg.author.id.0).await;
+        if spam {
+            METRICS.dropped("spam");
+            self.report_strike(
+                guild_id,
+                msg.author.id.0,
+                &msg.author.name,
+                "spam",
+                mute.as_ref(),
+            )
+            .await;
+        }
+        if let Some(mute) = mute {
+            self.say(
+                ctx,
+                msg.channel_id,
+                &format!(
+                    "{}, you're sending messages too quickly, I'll ignore you for {}.",
+                    msg.author.name,
+                    describe_duration(mute.duration)
+                ),
+            )
+            .await;
         }
+        spam
     }
 
-    async fn receive_message(&self) -> Option<QueuedMessage> {
-        let mut receiver = self.receiver.lock().await;
-        receiver.recv().await
+    // Posts to the guild's mod-log channel if it has one, and always to the console
+    pub async fn mod_log(&self, guild_id: u64, text: &str) {
+        info!(guild = guild_id, "Mod log: {}", text);
+
+        let channel = self.settings.get(guild_id).await.mod_log_channel;
+        if let (Some(channel), Some(http)) = (channel, self.http.get()) {
+            if let Err(e) = ChannelId(channel).say(http, text).await {
+                warn!("Failed to post to the mod log of guild {}: {}", guild_id, e);
+            }
+        }
+    }
+
+    // Swaps the reply just added to a channel's conversation for its moderated version
+    async fn replace_last_reply(&self, channel_id: u64, reply: &str, replacement: &str) {
+        let mut conversations = self.conversations.lock().await;
+        let last = conversations
+            .get_mut(&channel_id)
+            .and_then(|conversation_entry| conversation_entry.0.history.last_mut());
+        if let Some(last) = last {
+            if last.role == Role::Assistant && last.content == reply {
+                last.content = replacement.to_string();
+            }
+        }
+    }
+
+    // A persona chosen by a user wins if the channel allows switching, then one pinned by an admin
+    async fn resolve_persona(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        category_id: Option<u64>,
+    ) -> Option<Persona> {
+        let settings = self.settings.get(guild_id?).await;
+
+        if settings.allows_persona_switch(channel_id, category_id) {
+            let chosen_personas = self.chosen_personas.lock().await;
+            if let Some(persona) = chosen_personas.get(&channel_id) {
+                return Some(persona.clone());
+            }
+        }
+
+        settings.pinned_persona(channel_id, category_id).cloned()
+    }
+
+    async fn needs_new_conversation(&self, channel_id: u64) -> bool {
+        let conversations = self.conversations.lock().await;
+        conversations
+            .get(&channel_id)
+            .is_none_or(conversation_expired)
+    }
+
+    pub async fn channel_category(&self, ctx: &Context, channel_id: ChannelId) -> Option<u64> {
+        if let Some(category_id) = self.channel_categories.lock().await.get(&channel_id.0) {
+            return *category_id;
+        }
+
+        match channel_id.to_channel(&ctx.http).await {
+            Ok(Channel::Guild(channel)) => self.update_channel(&channel).await,
+            Ok(_) => {
+                self.channel_categories
+                    .lock()
+                    .await
+                    .insert(channel_id.0, None);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to fetch channel {}: {}", channel_id, e);
+                None
+            }
+        }
+    }
+
+    // Caches what the bot needs to know about a guild channel, also called when a channel is edited
+    pub async fn update_channel(&self, channel: &GuildChannel) -> Option<u64> {
+        let category_id = channel.category_id.map(|category_id| category_id.0);
+        self.names
+            .lock()
+            .await
+            .insert(channel.id.0, channel.name.clone());
+        self.set_channel_nsfw(channel.id.0, channel.is_nsfw()).await;
+        self.channel_categories
+            .lock()
+            .await
+            .insert(channel.id.0, category_id);
+        category_id
+    }
+
+    // A conversation started while the channel was NSFW may use a restricted persona,
+    // so it starts over once the channel isn't, and a reset from before can no longer bring it back
+    pub async fn set_channel_nsfw(&self, channel_id: u64, nsfw: bool) {
+        if nsfw {
+            self.nsfw_channels.lock().await.insert(channel_id);
+            return;
+        }
+        if !self.nsfw_channels.lock().await.remove(&channel_id) {
+            return;
+        }
+
+        let mut conversations = self.conversations.lock().await;
+        self.full_reset(&mut conversations, channel_id);
+        self.reset_undo.lock().await.retain(|_, undo| {
+            !undo
+                .conversations
+                .iter()
+                .any(|reset| reset.channel_id == channel_id)
+        });
+    }
+
+    pub async fn channel_nsfw(&self, ctx: &Context, channel_id: ChannelId) -> bool {
+        self.channel_category(ctx, channel_id).await;
+        self.nsfw_channels.lock().await.contains(&channel_id.0)
+    }
+
+    // Only guild channels have a name, it is looked up together with the category
+    pub async fn channel_name(&self, ctx: &Context, channel_id: ChannelId) -> Option<String> {
+        self.channel_category(ctx, channel_id).await;
+        self.names.lock().await.get(&channel_id.0).cloned()
+    }
+
+    pub async fn guild_name(&self, ctx: &Context, guild_id: GuildId) -> Option<String> {
+        if let Some(name) = self.names.lock().await.get(&guild_id.0) {
+            return Some(name.clone());
+        }
+
+        match guild_id.to_partial_guild(&ctx.http).await {
+            Ok(guild) => {
+                self.names
+                    .lock()
+                    .await
+                    .insert(guild_id.0, guild.name.clone());
+                Some(guild.name)
+            }
+            Err(e) => {
+                warn!("Failed to fetch guild {}: {}", guild_id, e);
+                None
+            }
+        }
+    }
+
+    // Applies the current persona to the conversation of a channel, or of every known channel in a category.
+    // Returns the channels whose conversation was switched.
+    pub async fn switch_personas_in(&self, guild_id: u64, id: u64) -> Vec<u64> {
+        let channels: Vec<(u64, Option<u64>)> = {
+            let channel_categories = self.channel_categories.lock().await;
+            let mut channels: Vec<(u64, Option<u64>)> = channel_categories
+                .iter()
+                .filter(|(_, category_id)| **category_id == Some(id))
+                .map(|(channel_id, category_id)| (*channel_id, *category_id))
+                .collect();
+            channels.push((id, channel_categories.get(&id).copied().flatten()));
+            channels
+        };
+
+        let settings = self.settings.get(guild_id).await;
+        let mut switched = Vec::new();
+        for (channel_id, category_id) in channels {
+            let persona = self
+                .resolve_persona(Some(guild_id), channel_id, category_id)
+                .await;
+            let nsfw = self.nsfw_channels.lock().await.contains(&channel_id);
+            let access = settings.preset_access(nsfw, None);
+            if self
+                .switch_persona(channel_id, persona, &settings.tone, &access)
+                .await
+            {
+                switched.push(channel_id);
+            }
+        }
+        switched
+    }
+
+    // Swaps the system message of a channel's conversation for the persona's, keeping the rest of the history.
+    // Without a persona the router picks one based on the latest message.
+    async fn switch_persona(
+        &self,
+        channel_id: u64,
+        persona: Option<Persona>,
+        tone_policy: &TonePolicy,
+        access: &PresetAccess,
+    ) -> bool {
+        let last_message = {
+            let conversations = self.conversations.lock().await;
+            match conversations.get(&channel_id) {
+                Some(conversation_entry) => conversation_entry
+                    .0
+                    .history
+                    .iter()
+                    .rev()
+                    .find(|message| message.role == Role::User)
+                    .map(|message| message.content.clone())
+                    .unwrap_or_default(),
+                None => return false,
+            }
+        };
+
+        let persona = match persona {
+            Some(persona) => persona,
+            None => {
+                let decision = self.router.route(&last_message, access).await;
+                Persona::Preset(decision.preset.name.to_string())
+            }
+        };
+        let variables = TemplateVariables {
+            channel: self
+                .names
+                .lock()
+                .await
+                .get(&channel_id)
+                .cloned()
+                .unwrap_or_default(),
+            time: current_time(),
+            // The message was scored when it was answered, the channel's mood stands in for it
+            tone: tone_policy.tone(
+                self.moods
+                    .channel(channel_id)
+                    .await
+                    .map_or(0.0, |mood| mood.average),
+            ),
+            ..Default::default()
+        };
+        let preset =
+            get_preset_based_on_sentiment(&last_message, &variables, Some(&persona), access);
+
+        let mut conversations = self.conversations.lock().await;
+        let conversation_entry = match conversations.get_mut(&channel_id) {
+            Some(conversation_entry) => conversation_entry,
+            None => return false,
+        };
+
+        match conversation_entry.0.history.first_mut() {
+            Some(message) if message.role == Role::System => message.content = preset,
+            _ => conversation_entry.0.history.insert(0, Turn::system(preset)),
+        }
+
+        info!(
+            "Switched the persona for channel {} to {}, keeping {} messages",
+            channel_id,
+            persona,
+            conversation_entry.0.history.len() - 1
+        );
+        true
     }
 
-    async fn chatbot_response(&self, queued_message: &QueuedMessage) -> Result<String> {
-        let message_text = queued_message.author_name.clone() + ": " + &queued_message.content;
-        self.chatbot(queued_message.channel_id, &message_text).await
-        // it might be here where it crashes
+    // Lets the new persona announce itself with one short line, which stays in the history
+    pub async fn acknowledge_switch(
+        &self,
+        ctx: &Context,
+        channel_id: ChannelId,
+        action: ModerationAction,
+    ) {
+        let mut conversations = self.conversations.lock().await;
+        let conversation_entry = match conversations.get_mut(&channel_id.0) {
+            Some(conversation_entry) => conversation_entry,
+            None => return,
+        };
+
+        let mut history = conversation_entry.0.history.clone();
+        history.push(Turn::system(
+            "Your persona has just been switched. Briefly acknowledge the change, in character, in one short sentence.",
+        ));
+
+        let acknowledgement = match self.chat_client.send(&history).await {
+            Ok(response) => self.strip_speaker_prefix(channel_id.0, &response).await,
+            Err(e) => {
+                warn!("Failed to acknowledge the persona switch: {}", e);
+                return;
+            }
+        };
+
+        // A blocked acknowledgement is simply left out, the switch was already confirmed
+        let (acknowledgement, warning) = match self
+            .moderation
+            .apply(
+                &acknowledgement,
+                action,
+                &format!("a persona acknowledgement in channel {}", channel_id),
+            )
+            .await
+        {
+            Outcome::Allowed => (acknowledgement, None),
+            Outcome::Blocked => return,
+            Outcome::Redacted(redacted) => (redacted, None),
+            Outcome::Warned(categories) => {
+                (acknowledgement, Some(moderation::warning(&categories)))
+            }
+        };
+
+        conversation_entry
+            .0
+            .history
+            .push(Turn::assistant(acknowledgement.clone()));
+        conversation_entry.1 = Utc::now();
+        drop(conversations);
+
+        match warning {
+            Some(warning) => {
+                self.say(
+                    ctx,
+                    channel_id,
+                    &format!("{}\n{}", warning, acknowledgement),
+                )
+                .await
+            }
+            None => self.say(ctx, channel_id, &acknowledgement).await,
+        }
     }
 
     async fn send_response(
@@ -86,18 +1036,37 @@ impl Handler {
         queued_message: &QueuedMessage,
         response: String,
     ) {
-        println!("Response: {}", response);
+        debug!("Response: {}", logging::content(&response));
 
-        let _ = ChannelId(queued_message.channel_id)
-            .send_message(&http, |m| {
-                m.content(response);
-                m.tts(true)
-            })
-            .await;
+        // Citations and notices can take a reply past Discord's 2000 character limit
+        for part in split_message(&response, 2000) {
+            let sent = ChannelId(queued_message.channel_id)
+                .send_message(&http, |m| {
+                    m.content(part);
+                    m.tts(true)
+                })
+                .await;
+            if let Err(e) = sent {
+                warn!("Failed to send the response: {}", e);
+                METRICS.dropped("send_failed");
+                return;
+            }
+        }
+        METRICS.messages_responded.inc();
+    }
+
+    // Sends a plain message, split up to stay under Discord's 2000 character limit
+    pub async fn say(&self, ctx: &Context, channel_id: ChannelId, text: &str) {
+        for part in split_message(text, 2000) {
+            if let Err(e) = channel_id.say(&ctx.http, part).await {
+                warn!("Failed to send message: {}", e);
+            }
+        }
     }
 
     async fn handle_error(&self, error: chatgpt::err::Error, queued_message: &QueuedMessage) {
-        eprintln!("Error: {}", error);
+        error!("Error: {}", error);
+        METRICS.dropped("error");
 
         let mut conversations = self.conversations.lock().await;
 
@@ -105,57 +1074,97 @@ impl Handler {
         //self.handle_reset(&mut conversations, );
     }
 
-    pub async fn chatbot(&self, channel_id: u64, input_str: &String) -> Result<String> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn chatbot(
+        &self,
+        channel_id: u64,
+        message: Turn,
+        context: &SystemPrompt,
+        persona: Option<&Persona>,
+        access: &PresetAccess,
+        variables: &TemplateVariables,
+    ) -> Result<String> {
         // Lock the conversations HashMap
         let mut conversations = self.conversations.lock().await;
 
         let conversation_entry = self
-            .get_or_create_conversation(&mut conversations, channel_id, input_str)
+            .get_or_create_conversation(
+                &mut conversations,
+                channel_id,
+                &message.content,
+                persona,
+                access,
+                variables,
+            )
             .await;
 
         self.handle_reset(conversation_entry, 10);
 
-        // Send the user's message to the conversation and receive a response
-        let response = conversation_entry.0.send_message(input_str).await;
+        // Context such as recalled memories only applies to this turn,
+        // so it is added just before the message and removed again afterwards
+        let context_start = conversation_entry.0.history.len();
+        if !context.is_empty() {
+            conversation_entry
+                .0
+                .history
+                .push(Turn::system(context.compose()));
+        }
 
-        if response.is_ok() {
-            // Update the conversation's last message time to the current time
-            conversation_entry.1 = Utc::now();
+        // Send the user's message to the conversation and receive a response
+        conversation_entry.0.history.push(message);
+        let response = self.chat_client.send(&conversation_entry.0.history).await;
 
-            // Return the response content as a String
-            Ok(response?.message().content.to_string())
-        }
-        else {
-            Ok("Error".to_string())
+        if !context.is_empty() {
+            conversation_entry.0.history.remove(context_start);
         }
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                // A message that was never answered doesn't stay in the conversation
+                conversation_entry.0.history.truncate(context_start);
+                return Err(e);
+            }
+        };
+
+        let response = self.strip_speaker_prefix(channel_id, &response).await;
+        conversation_entry
+            .0
+            .history
+            .push(Turn::assistant(response.clone()));
+
+        // Update the conversation's last message time to the current time
+        conversation_entry.1 = Utc::now();
+
+        Ok(response)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn get_or_create_conversation<'a>(
         &'a self,
-        conversations: &'a mut HashMap<u64, (Conversation, chrono::DateTime<Utc>)>,
+        conversations: &'a mut HashMap<u64, ConversationEntry>,
         channel_id: u64,
-        input_str: &String,
-    ) -> &mut (Conversation, chrono::DateTime<Utc>) {
-        let now = Utc::now();
-
+        input_str: &str,
+        persona: Option<&Persona>,
+        access: &PresetAccess,
+        variables: &TemplateVariables,
+    ) -> &'a mut ConversationEntry {
         // Attempt to find an existing conversation for the given channel_id
         // If it doesn't exist, create a new conversation with the chosen preset and store the current timestamp as the last message time
         let conversation_entry = conversations.entry(channel_id).or_insert_with(|| {
-            let preset = get_preset_based_on_sentiment(input_str);
-            println!(
+            let preset = get_preset_based_on_sentiment(input_str, variables, persona, access);
+            debug!(
                 "Generating a new conversation for channel: {}, with preset: {}",
-                channel_id, preset
+                channel_id,
+                logging::content(&preset)
             );
-            (
-                self.chat_gpt_client.new_conversation_directed(preset),
-                Utc::now(),
-            )
+            (Conversation::new(preset), Utc::now())
         });
 
-        // Check if the conversation's last message time is older than 10 minutes
+        // Check if the conversation's last message time is older than 5 minutes
         // If it is, recreate the conversation with the chosen preset and update the last message time to the current time
-        if now.signed_duration_since(conversation_entry.1) > Duration::minutes(5) {
-            self.refresh_conversation(conversation_entry, &input_str);
+        if conversation_expired(conversation_entry) {
+            self.refresh_conversation(conversation_entry, input_str, persona, access, variables);
         }
 
         conversation_entry
@@ -163,31 +1172,173 @@ impl Handler {
 
     fn refresh_conversation(
         &self,
-        conversation_entry: &mut (Conversation, chrono::DateTime<Utc>),
-        input_str: &String,
+        conversation_entry: &mut ConversationEntry,
+        input_str: &str,
+        persona: Option<&Persona>,
+        access: &PresetAccess,
+        variables: &TemplateVariables,
     ) {
-        let preset = get_preset_based_on_sentiment(input_str);
-        println!("Refreshing the conversation with preset: {}", preset);
-        *conversation_entry = (
-            self.chat_gpt_client.new_conversation_directed(preset),
-            Utc::now(),
+        let preset = get_preset_based_on_sentiment(input_str, variables, persona, access);
+        debug!(
+            "Refreshing the conversation with preset: {}",
+            logging::content(&preset)
         );
+        *conversation_entry = (Conversation::new(preset), Utc::now());
     }
 
-    fn full_reset(
+    // Clears conversations in one go, under a single lock so no reply lands halfway through.
+    // Returns how many conversations were changed, what they were can be brought back with undo_reset.
+    pub async fn reset(&self, scope: ResetScope, channel_id: u64, user_id: u64) -> usize {
+        let mut conversations = self.conversations.lock().await;
+        let mut previous = Vec::new();
+        match scope {
+            ResetScope::Mine {
+                channel_id,
+                speaker,
+            } => {
+                if let Some(conversation_entry) = conversations.get_mut(&channel_id) {
+                    let before = conversation_entry.clone();
+                    if conversation_entry.0.remove_speaker(&speaker) > 0 {
+                        previous.push(ResetConversation {
+                            channel_id,
+                            before,
+                            kept: conversation_entry.0.history.clone(),
+                        });
+                    }
+                }
+            }
+            ResetScope::Channels(channels) => {
+                for channel in channels {
+                    if let Some(before) = conversations.remove(&channel) {
+                        previous.push(ResetConversation {
+                            channel_id: channel,
+                            before,
+                            kept: Vec::new(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let count = previous.len();
+        info!(
+            "{} reset {} conversations from channel {}",
+            user_id, count, channel_id
+        );
+        if count > 0 {
+            self.reset_undo.lock().await.insert(
+                channel_id,
+                ResetUndo {
+                    user_id,
+                    at: Utc::now(),
+                    conversations: previous,
+                },
+            );
+        }
+        count
+    }
+
+    // Removes a user's turns, and the replies to them, from the conversations of these channels, along with their names there.
+    // Resets that could still be undone there are dropped too, they may hold the same turns.
+    // Returns how many conversations had turns removed.
+    pub async fn forget_speaker(&self, channels: &[u64], user_id: u64) -> usize {
+        // Replies are stripped of speaker labels while the conversations are locked, so the names are taken first
+        let names: Vec<(u64, String)> = {
+            let mut speakers = self.speakers.lock().await;
+            channels
+                .iter()
+                .filter_map(|channel_id| {
+                    let speaker = speakers.remove(*channel_id, user_id)?;
+                    Some((*channel_id, speaker))
+                })
+                .collect()
+        };
+
+        let mut conversations = self.conversations.lock().await;
+        let mut changed = 0;
+        for (channel_id, speaker) in names {
+            if let Some((conversation, _)) = conversations.get_mut(&channel_id) {
+                if conversation.remove_speaker(&speaker) > 0 {
+                    changed += 1;
+                }
+            }
+        }
+
+        self.reset_undo.lock().await.retain(|_, undo| {
+            !undo
+                .conversations
+                .iter()
+                .any(|reset| channels.contains(&reset.channel_id))
+        });
+        changed
+    }
+
+    // Brings back what the last reset in a channel cleared, for whoever asked for it or an admin.
+    // Turns added since the reset are kept after the restored ones, unless the conversation changed too much to tell which they are.
+    pub async fn undo_reset(
         &self,
-        conversations: &mut HashMap<u64, (Conversation, chrono::DateTime<Utc>)>,
         channel_id: u64,
-    ) {
+        user_id: u64,
+        admin: bool,
+    ) -> std::result::Result<usize, &'static str> {
+        let mut conversations = self.conversations.lock().await;
+        let mut undos = self.reset_undo.lock().await;
+
+        let status = undos.get(&channel_id).map(|undo| {
+            (
+                Utc::now() - undo.at <= Duration::minutes(UNDO_WINDOW_MINUTES),
+                undo.user_id == user_id || admin,
+            )
+        });
+        match status {
+            None => return Err("There is no reset to undo here."),
+            Some((false, _)) => {
+                undos.remove(&channel_id);
+                return Err("The last reset here is too old to undo.");
+            }
+            Some((true, false)) => {
+                return Err("Only whoever asked for the reset, or an admin, can undo it.")
+            }
+            Some((true, true)) => {}
+        }
+
+        let undo = undos.remove(&channel_id).unwrap();
+        let mut restored = Vec::with_capacity(undo.conversations.len());
+        for reset in undo.conversations {
+            let added = match conversations.get(&reset.channel_id) {
+                None => Vec::new(),
+                // A conversation started after the reset, everything but its system message is new
+                Some((current, _)) if reset.kept.is_empty() => current.history[1..].to_vec(),
+                Some((current, _)) if current.history.starts_with(&reset.kept) => {
+                    current.history[reset.kept.len()..].to_vec()
+                }
+                // Trimmed or started over since, undoing would lose or repeat turns
+                Some(_) => {
+                    return Err("The conversation changed too much since the reset to undo it.")
+                }
+            };
+            restored.push((reset.channel_id, reset.before.0, added));
+        }
+
+        let count = restored.len();
+        // Restored conversations count as active again, otherwise they'd expire right away
+        for (channel, mut conversation, added) in restored {
+            conversation.history.extend(added);
+            conversations.insert(channel, (conversation, Utc::now()));
+        }
+        info!(
+            "{} undid the reset of {} conversations from channel {}",
+            user_id, count, channel_id
+        );
+        Ok(count)
+    }
+
+    fn full_reset(&self, conversations: &mut HashMap<u64, ConversationEntry>, channel_id: u64) {
         conversations.remove(&channel_id);
-        println!("Conversation for channel, {}, has been reset", channel_id);
+        info!("Conversation for channel {} has been reset", channel_id);
     }
 
-    fn handle_reset(
-        &self,
-        conversation_entry: &mut (Conversation, chrono::DateTime<Utc>),
-        memory: usize,
-    ) {
+    fn handle_reset(&self, conversation_entry: &mut ConversationEntry, memory: usize) {
         // If the conversation history contains more than 20 messages, recreate the conversation with the last 10 messages and pre-prompt message
 
         if conversation_entry.0.history.len() > 20 {
@@ -195,7 +1346,7 @@ impl Handler {
             let pre_prompt_message = conversation_entry
                 .0
                 .history
-                .get(0)
+                .first()
                 .cloned()
                 .expect("Failed to get history handler.rs");
 
@@ -211,15 +1362,426 @@ impl Handler {
             // load the initial message, gives more consistent responses.
             message_memory.insert(0, pre_prompt_message);
 
-            println!("Recreating the conversation with the following messages:");
+            debug!(
+                "Recreating the conversation with the following {} messages:",
+                message_memory.len()
+            );
             for message in &message_memory {
-                println!("{message:#?}")
+                debug!("{:?}: {}", message.role, logging::content(&message.content))
             }
 
             *conversation_entry = (
-                Conversation::new_with_history(self.chat_gpt_client.clone(), message_memory),
+                Conversation {
+                    history: message_memory,
+                },
                 Utc::now(),
             );
         }
     }
 }
+
+impl Handler {
+    // Removes a leaked "<speaker>:" label from the start of a reply in the channel
+    async fn strip_speaker_prefix(&self, channel_id: u64, response: &str) -> String {
+        let speakers = self.speakers.lock().await;
+        let names = speakers
+            .in_channel(channel_id)
+            .map(String::as_str)
+            .chain(self.bot_name.get().map(String::as_str));
+        strip_speaker_prefix(response, names)
+    }
+}
+
+// Adds a notice about the author, like a mute, after a reply
+fn with_notice(response: String, notice: Option<String>) -> String {
+    match notice {
+        Some(notice) => format!("{}\n{}", response, notice),
+        None => response,
+    }
+}
+
+fn current_time() -> String {
+    Utc::now().format("%A %Y-%m-%d %H:%M UTC").to_string()
+}
+
+// A one-off request that never touches the channel's active conversation
+pub async fn complete(client: &ChatGPT, instructions: &str, input: &str) -> Result<String> {
+    let history = vec![
+        ChatMessage {
+            role: Role::System,
+            content: instructions.to_string(),
+        },
+        ChatMessage {
+            role: Role::User,
+            content: input.to_string(),
+        },
+    ];
+
+    let model = client.config.engine.to_string();
+    let started = Instant::now();
+    let response = client.send_history(&history).await;
+    METRICS
+        .backend_latency
+        .with_label_values(&[&model])
+        .observe(started.elapsed().as_secs_f64());
+
+    let response = response?;
+    METRICS.record_tokens(
+        &model,
+        response.usage.prompt_tokens.into(),
+        response.usage.completion_tokens.into(),
+    );
+    Ok(response.message().content.to_string())
+}
+
+// Splits text into pieces of at most `limit` bytes, preferring to break on newlines
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        while current.len() + line.len() > limit {
+            if !current.is_empty() {
+                parts.push(std::mem::take(&mut current));
+                continue;
+            }
+
+            let mut end = limit;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            parts.push(line[..end].to_string());
+            line = &line[end..];
+        }
+        current.push_str(line);
+    }
+
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::MockBackend;
+
+    #[test]
+    fn messages_are_split_on_newlines_where_possible() {
+        assert_eq!(split_message("short", 10), ["short"]);
+        assert_eq!(
+            split_message("one\ntwo\nthree\n", 8),
+            ["one\ntwo\n", "three\n"]
+        );
+        // Lines longer than the limit are cut, on a character boundary
+        assert_eq!(split_message("abcdefghij\nk", 4), ["abcd", "efgh", "ij\nk"]);
+        assert_eq!(split_message("ééé", 3), ["é", "é", "é"]);
+        assert!(split_message("\n\n", 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_errors_and_leave_no_unanswered_turn() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::failing())).await;
+
+        assert!(handler
+            .chatbot_response(&QueuedMessage::test(8001, "hello"))
+            .await
+            .is_err());
+        let conversations = handler.conversations.lock().await;
+        let history = &conversations.get(&8002).unwrap().0.history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].role, chatgpt::types::Role::System);
+    }
+
+    fn contents(entry: Option<&ConversationEntry>) -> Vec<String> {
+        entry
+            .unwrap()
+            .0
+            .history
+            .iter()
+            .map(|turn| turn.content.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resets_can_be_undone_by_their_author() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::new("hi"))).await;
+        let (channel, other_channel) = (7001, 7002);
+        for channel_id in [channel, other_channel] {
+            handler
+                .conversations
+                .lock()
+                .await
+                .insert(channel_id, (Conversation::new("Be nice."), Utc::now()));
+        }
+
+        let reset = handler
+            .reset(
+                ResetScope::Channels(vec![channel, other_channel]),
+                channel,
+                1,
+            )
+            .await;
+        assert_eq!(reset, 2);
+        assert!(handler.conversations.lock().await.get(&channel).is_none());
+
+        assert!(handler.undo_reset(channel, 2, false).await.is_err());
+        assert_eq!(handler.undo_reset(channel, 1, false).await, Ok(2));
+        assert!(handler
+            .conversations
+            .lock()
+            .await
+            .contains_key(&other_channel));
+        // An undo can't be repeated
+        assert!(handler.undo_reset(channel, 1, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn undoing_a_reset_keeps_newer_turns() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::new("hi"))).await;
+        let channel = 7101;
+        let mut conversation = Conversation::new("Be nice.");
+        conversation.history.extend([
+            Turn::user("alice", "hi"),
+            Turn::assistant("hello alice"),
+            Turn::user("bob", "hey"),
+            Turn::assistant("hello bob"),
+        ]);
+        handler
+            .conversations
+            .lock()
+            .await
+            .insert(channel, (conversation, Utc::now()));
+
+        let mine = ResetScope::Mine {
+            channel_id: channel,
+            speaker: "alice".to_string(),
+        };
+        assert_eq!(handler.reset(mine, channel, 1).await, 1);
+        handler
+            .conversations
+            .lock()
+            .await
+            .get_mut(&channel)
+            .unwrap()
+            .0
+            .history
+            .extend([Turn::user("bob", "still there?"), Turn::assistant("yes")]);
+
+        assert_eq!(handler.undo_reset(channel, 1, false).await, Ok(1));
+        assert_eq!(
+            contents(handler.conversations.lock().await.get(&channel)),
+            [
+                "Be nice.",
+                "hi",
+                "hello alice",
+                "hey",
+                "hello bob",
+                "still there?",
+                "yes"
+            ]
+        );
+
+        // A conversation started after a full reset is added to the restored one
+        handler
+            .reset(ResetScope::Channels(vec![channel]), channel, 1)
+            .await;
+        let mut conversation = Conversation::new("Be brief.");
+        conversation.history.push(Turn::user("bob", "again"));
+        handler
+            .conversations
+            .lock()
+            .await
+            .insert(channel, (conversation, Utc::now()));
+
+        assert_eq!(handler.undo_reset(channel, 1, false).await, Ok(1));
+        let restored = contents(handler.conversations.lock().await.get(&channel));
+        assert_eq!(restored.first().unwrap(), "Be nice.");
+        assert_eq!(restored.last().unwrap(), "again");
+        assert!(!restored.iter().any(|content| content == "Be brief."));
+    }
+
+    #[tokio::test]
+    async fn undoing_a_reset_refuses_when_the_conversation_started_over() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::new("hi"))).await;
+        let channel = 7201;
+        let mut conversation = Conversation::new("Be nice.");
+        conversation
+            .history
+            .extend([Turn::user("alice", "hi"), Turn::assistant("hello alice")]);
+        handler
+            .conversations
+            .lock()
+            .await
+            .insert(channel, (conversation, Utc::now()));
+
+        let mine = ResetScope::Mine {
+            channel_id: channel,
+            speaker: "alice".to_string(),
+        };
+        assert_eq!(handler.reset(mine, channel, 1).await, 1);
+        handler
+            .conversations
+            .lock()
+            .await
+            .insert(channel, (Conversation::new("Be brief."), Utc::now()));
+
+        assert!(handler.undo_reset(channel, 1, false).await.is_err());
+        assert_eq!(
+            contents(handler.conversations.lock().await.get(&channel)),
+            ["Be brief."]
+        );
+    }
+
+    #[tokio::test]
+    async fn forgetting_a_speaker_removes_their_turns_from_the_listed_channels() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::new("hi"))).await;
+        let (channel, other_channel, elsewhere) = (7401, 7402, 7403);
+        for channel_id in [channel, other_channel, elsewhere] {
+            let alice = handler.speakers.lock().await.name(channel_id, 42, "alice");
+            let mut conversation = Conversation::new("Be nice.");
+            conversation.history.extend([
+                Turn::user(&alice, "my secret"),
+                Turn::assistant("your secret is safe"),
+                Turn::user("bob", "hey"),
+            ]);
+            handler
+                .conversations
+                .lock()
+                .await
+                .insert(channel_id, (conversation, Utc::now()));
+        }
+
+        assert_eq!(
+            handler
+                .forget_speaker(&[channel, other_channel, 7404], 42)
+                .await,
+            2
+        );
+        let conversations = handler.conversations.lock().await;
+        assert_eq!(contents(conversations.get(&channel)), ["Be nice.", "hey"]);
+        assert_eq!(
+            contents(conversations.get(&other_channel)),
+            ["Be nice.", "hey"]
+        );
+        assert_eq!(contents(conversations.get(&elsewhere)).len(), 4);
+        drop(conversations);
+
+        // Their names there are gone too, only the channel left out still knows them
+        let speakers = handler.speakers.lock().await;
+        assert_eq!(speakers.in_channel(channel).count(), 0);
+        assert_eq!(speakers.in_channel(elsewhere).count(), 1);
+        drop(speakers);
+
+        // Users the bot never talked to have nothing to forget
+        assert_eq!(handler.forget_speaker(&[channel], 43).await, 0);
+    }
+
+    #[tokio::test]
+    async fn channels_that_stop_being_nsfw_start_over() {
+        let handler = Handler::with_backend(Arc::new(MockBackend::new("hi"))).await;
+        let (channel, sfw_channel) = (7501, 7502);
+        handler.set_channel_nsfw(channel, true).await;
+        for channel_id in [channel, sfw_channel] {
+            handler
+                .conversations
+                .lock()
+                .await
+                .insert(channel_id, (Conversation::new("Be drunk."), Utc::now()));
+        }
+        handler
+            .reset(ResetScope::Channels(vec![channel]), sfw_channel, 1)
+            .await;
+        handler
+            .conversations
+            .lock()
+            .await
+            .insert(channel, (Conversation::new("Be drunk."), Utc::now()));
+
+        // Already safe for work, nothing changes
+        handler.set_channel_nsfw(sfw_channel, false).await;
+        assert!(handler
+            .conversations
+            .lock()
+            .await
+            .contains_key(&sfw_channel));
+
+        handler.set_channel_nsfw(channel, false).await;
+        assert!(!handler.conversations.lock().await.contains_key(&channel));
+        assert!(handler.undo_reset(sfw_channel, 1, false).await.is_err());
+    }
+
+    // Each message needs an id of its own, the queue ignores ids it already has
+    fn queued(message_id: u64) -> QueuedMessage {
+        QueuedMessage {
+            message_id,
+            ..QueuedMessage::test(1, &format!("message {}", message_id))
+        }
+    }
+
+    #[tokio::test]
+    async fn restarted_workers_answer_in_flight_messages_once() {
+        let handler = Arc::new(Handler::with_backend(Arc::new(MockBackend::new("hi"))).await);
+        let (answered, mut answers) = tokio::sync::mpsc::unbounded_channel();
+
+        // The first worker takes the message and never finishes it
+        let (taken, was_taken) = tokio::sync::oneshot::channel();
+        let stuck = handler.clone();
+        handler
+            .restart_worker(async move {
+                let _ = taken.send(stuck.queue.next().await.message_id);
+                std::future::pending::<()>().await
+            })
+            .await;
+        handler.queue.push(queued(1)).await;
+        assert_eq!(was_taken.await.unwrap(), 1);
+
+        let worker = handler.clone();
+        handler
+            .restart_worker(async move {
+                loop {
+                    let queued_message = worker.queue.next().await;
+                    answered.send(queued_message.message_id).unwrap();
+                    worker.queue.ack(queued_message.message_id).await;
+                }
+            })
+            .await;
+        handler.queue.push(queued(2)).await;
+
+        assert_eq!(answers.recv().await, Some(1));
+        assert_eq!(answers.recv().await, Some(2));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(answers.try_recv().is_err());
+        assert_eq!(handler.health.report(false).await.worker_restarts, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_queued_messages_until_the_timeout() {
+        let handler = Arc::new(Handler::with_backend(Arc::new(MockBackend::new("hi"))).await);
+        handler.queue.push(queued(rand::random())).await;
+        let worker = handler.clone();
+        tokio::spawn(async move {
+            let queued_message = worker.queue.next().await;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            worker.queue.ack(queued_message.message_id).await;
+        });
+
+        let started = Instant::now();
+        handler.shutdown(std::time::Duration::from_secs(10)).await;
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(handler.queue.len().await, 0);
+        assert!(!handler.health.report(false).await.ready());
+
+        // A message nobody answers holds the shutdown up only until the timeout, and stays queued
+        handler.queue.push(queued(rand::random())).await;
+        let started = Instant::now();
+        handler
+            .shutdown(std::time::Duration::from_millis(200))
+            .await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+        assert_eq!(handler.queue.len().await, 1);
+    }
+}
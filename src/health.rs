@@ -0,0 +1,199 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::chat_client::ChatBackend;
+
+// A message taking longer than this to answer means the queue worker is stuck
+const STUCK_AFTER: Duration = Duration::from_secs(5 * 60);
+// How long a backend check is trusted before the backend is asked again
+const BACKEND_CHECK_TTL: Duration = Duration::from_secs(60);
+
+// What the health and readiness endpoints report on: the gateway connection,
+// the queue worker and whether the model can be reached
+pub struct Health {
+    gateway: AtomicBool,
+    watchdog: AtomicBool,
+    shutting_down: AtomicBool,
+    worker: std::sync::Mutex<Worker>,
+    backend: Arc<dyn ChatBackend>,
+    backend_check: Mutex<Option<(Instant, bool)>>,
+}
+
+#[derive(Default)]
+struct Worker {
+    task: Option<JoinHandle<()>>,
+    // When the worker started on the message it is answering, if any
+    busy_since: Option<Instant>,
+    restarts: u32,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerStatus {
+    Running,
+    Stuck,
+    Dead,
+    // Not started yet, the bot hasn't connected to Discord
+    Stopped,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub gateway: bool,
+    pub shutting_down: bool,
+    pub worker: WorkerStatus,
+    pub worker_restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<bool>,
+}
+
+impl Report {
+    // The process is alive as long as its worker is, a lost gateway connection is retried by serenity
+    pub fn live(&self) -> bool {
+        !matches!(self.worker, WorkerStatus::Dead | WorkerStatus::Stuck)
+    }
+
+    pub fn ready(&self) -> bool {
+        self.gateway
+            && !self.shutting_down
+            && self.worker == WorkerStatus::Running
+            && self.backend != Some(false)
+    }
+}
+
+impl Health {
+    pub fn new(backend: Arc<dyn ChatBackend>) -> Self {
+        Health {
+            gateway: AtomicBool::new(false),
+            watchdog: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            worker: Default::default(),
+            backend,
+            backend_check: Mutex::new(None),
+        }
+    }
+
+    pub fn set_gateway(&self, connected: bool) {
+        self.gateway.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    // True only for the first caller, so a single watchdog looks after the worker
+    pub fn claim_watchdog(&self) -> bool {
+        !self.watchdog.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn worker_status(&self) -> WorkerStatus {
+        let worker = self.worker.lock().unwrap();
+        match &worker.task {
+            None => WorkerStatus::Stopped,
+            Some(task) if task.is_finished() => WorkerStatus::Dead,
+            Some(_)
+                if worker
+                    .busy_since
+                    .is_some_and(|since| since.elapsed() > STUCK_AFTER) =>
+            {
+                WorkerStatus::Stuck
+            }
+            Some(_) => WorkerStatus::Running,
+        }
+    }
+
+    // Stops the worker task, which stays in place until it is replaced
+    pub fn abort_worker(&self) {
+        if let Some(task) = &self.worker.lock().unwrap().task {
+            task.abort();
+        }
+    }
+
+    // Replaces the worker task, aborting the previous one if it is still around
+    pub fn set_worker(&self, task: JoinHandle<()>) {
+        let mut worker = self.worker.lock().unwrap();
+        if let Some(previous) = worker.task.replace(task) {
+            previous.abort();
+            worker.restarts += 1;
+        }
+        worker.busy_since = None;
+    }
+
+    pub fn begin_message(&self) {
+        self.worker.lock().unwrap().busy_since = Some(Instant::now());
+    }
+
+    pub fn end_message(&self) {
+        self.worker.lock().unwrap().busy_since = None;
+    }
+
+    // Asks the backend whether it is reachable, at most once a minute
+    async fn backend_reachable(&self) -> bool {
+        let mut check = self.backend_check.lock().await;
+        if let Some((checked, reachable)) = *check {
+            if checked.elapsed() < BACKEND_CHECK_TTL {
+                return reachable;
+            }
+        }
+
+        let reachable = match self.backend.ping().await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("The chat backend is unreachable: {}", e);
+                false
+            }
+        };
+        *check = Some((Instant::now(), reachable));
+        reachable
+    }
+
+    // Liveness only looks at the process itself, readiness also checks the backend
+    pub async fn report(&self, check_backend: bool) -> Report {
+        let backend = match check_backend {
+            true => Some(self.backend_reachable().await),
+            false => None,
+        };
+        Report {
+            gateway: self.gateway.load(Ordering::Relaxed),
+            shutting_down: self.shutting_down(),
+            worker: self.worker_status(),
+            worker_restarts: self.worker.lock().unwrap().restarts,
+            backend,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::MockBackend;
+
+    #[tokio::test]
+    async fn reports_dead_workers_and_readiness() {
+        let health = Health::new(Arc::new(MockBackend::new("pong")));
+        assert_eq!(health.worker_status(), WorkerStatus::Stopped);
+        assert!(!health.report(true).await.ready());
+
+        health.set_gateway(true);
+        health.set_worker(tokio::spawn(std::future::pending()));
+        let report = health.report(true).await;
+        assert!(report.live() && report.ready());
+
+        // A worker that returned, or panicked, is dead until it is replaced
+        health.set_worker(tokio::spawn(async {}));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let report = health.report(false).await;
+        assert_eq!(report.worker, WorkerStatus::Dead);
+        assert_eq!(report.worker_restarts, 1);
+        assert!(!report.live());
+    }
+}
@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::embeddings::Embedder;
+use crate::storage::DataDir;
+use crate::vector_index::VectorIndex;
+
+const MAX_CHUNK_CHARS: usize = 1000;
+const KNOWLEDGE_CAPACITY: usize = 50_000;
+const RETRIEVE_COUNT: usize = 3;
+const RETRIEVE_MIN_SCORE: f32 = 0.3;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KnowledgeChunk {
+    pub document: String,
+    pub heading: Option<String>,
+    pub text: String,
+}
+
+impl KnowledgeChunk {
+    // How the chunk is referred to in a citation, e.g. "rules.md › Voice channels"
+    pub fn source(&self) -> String {
+        match &self.heading {
+            Some(heading) => format!("{} › {}", self.document, heading),
+            None => self.document.clone(),
+        }
+    }
+}
+
+// Documents uploaded by a guild's admins, chunked and indexed locally so they can be quoted accurately
+pub struct KnowledgeBase {
+    embedder: Arc<dyn Embedder>,
+    indexes: Mutex<HashMap<u64, VectorIndex<KnowledgeChunk>>>,
+    data: DataDir,
+}
+
+impl KnowledgeBase {
+    pub fn new(embedder: Arc<dyn Embedder>, data: DataDir) -> Self {
+        KnowledgeBase {
+            embedder,
+            indexes: Mutex::new(HashMap::new()),
+            data,
+        }
+    }
+
+    // Indexes a document, replacing any earlier version with the same name. Returns the number of chunks.
+    pub async fn add_document(
+        &self,
+        guild_id: u64,
+        document: &str,
+        content: &str,
+    ) -> chatgpt::Result<usize> {
+        let chunks = chunk_document(document, content);
+
+        // Embed everything before touching the index so a failure leaves the old version in place
+        let mut embedded = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let embedding = self
+                .embedder
+                .embed(&format!("{}\n{}", chunk.source(), chunk.text))
+                .await?;
+            embedded.push((embedding, chunk));
+        }
+
+        let mut indexes = self.indexes.lock().await;
+        let index = load_index(&self.data, &mut indexes, guild_id);
+        index.retain(|chunk| chunk.document != document).await;
+
+        let count = embedded.len();
+        for (embedding, chunk) in embedded {
+            index.insert(embedding, chunk).await;
+        }
+
+        info!(
+            "Indexed document {} for guild {} in {} chunks",
+            document, guild_id, count
+        );
+        Ok(count)
+    }
+
+    // Removes a document, returning whether it existed
+    pub async fn remove_document(&self, guild_id: u64, document: &str) -> bool {
+        let mut indexes = self.indexes.lock().await;
+        let index = load_index(&self.data, &mut indexes, guild_id);
+
+        let before = index.len();
+        index.retain(|chunk| chunk.document != document).await;
+        index.len() != before
+    }
+
+    // Every indexed document with its number of chunks, sorted by name
+    pub async fn documents(&self, guild_id: u64) -> Vec<(String, usize)> {
+        let mut indexes = self.indexes.lock().await;
+        let index = load_index(&self.data, &mut indexes, guild_id);
+
+        let mut documents: HashMap<&str, usize> = HashMap::new();
+        for chunk in index.items() {
+            *documents.entry(chunk.document.as_str()).or_default() += 1;
+        }
+
+        let mut documents: Vec<(String, usize)> = documents
+            .into_iter()
+            .map(|(document, count)| (document.to_string(), count))
+            .collect();
+        documents.sort();
+        documents
+    }
+
+    pub async fn retrieve(&self, guild_id: u64, query: &str) -> Vec<KnowledgeChunk> {
+        let embedding = match self.embedder.embed(query).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                warn!("Failed to embed message for knowledge retrieval: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut indexes = self.indexes.lock().await;
+        load_index(&self.data, &mut indexes, guild_id)
+            .search(&embedding, RETRIEVE_COUNT, RETRIEVE_MIN_SCORE, |_| true)
+            .into_iter()
+            .map(|(score, chunk)| {
+                debug!("Retrieved {} (score {:.2})", chunk.source(), score);
+                chunk.clone()
+            })
+            .collect()
+    }
+}
+
+// Builds the system message that hands the retrieved chunks to the model, numbered for citation
+pub fn knowledge_prompt(chunks: &[KnowledgeChunk]) -> String {
+    let sources = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| format!("[{}] ({})\n{}", index + 1, chunk.source(), chunk.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    format!(
+        "The following excerpts are from this server's documents. If they answer the message, \
+        quote them accurately instead of guessing, and cite them with their number like [1]. \
+        Ignore them if they are not relevant.\n\n{}",
+        sources
+    )
+}
+
+// Appends the sources the response actually cited, so readers can check them
+pub fn append_citations(response: &str, chunks: &[KnowledgeChunk]) -> String {
+    let cited: Vec<String> = chunks
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| response.contains(&format!("[{}]", index + 1)))
+        .map(|(index, chunk)| format!("[{}] {}", index + 1, chunk.source()))
+        .collect();
+
+    if cited.is_empty() {
+        return response.to_string();
+    }
+
+    format!("{}\n\n*Sources: {}*", response, cited.join(", "))
+}
+
+// Splits a markdown or text document into chunks, keeping track of the heading each one falls under
+pub fn chunk_document(document: &str, content: &str) -> Vec<KnowledgeChunk> {
+    let mut chunks = Vec::new();
+    let mut heading: Option<String> = None;
+    let mut current = String::new();
+
+    let mut flush = |heading: &Option<String>, current: &mut String| {
+        if !current.trim().is_empty() {
+            chunks.push(KnowledgeChunk {
+                document: document.to_string(),
+                heading: heading.clone(),
+                text: current.trim().to_string(),
+            });
+        }
+        current.clear();
+    };
+
+    for paragraph in content.split("\n\n") {
+        for line in paragraph.lines() {
+            if let Some(title) = heading_title(line) {
+                flush(&heading, &mut current);
+                heading = Some(title.to_string());
+                continue;
+            }
+
+            for piece in split_line(line, MAX_CHUNK_CHARS - 1) {
+                if current.len() + piece.len() >= MAX_CHUNK_CHARS {
+                    flush(&heading, &mut current);
+                }
+                current.push_str(piece);
+                current.push('\n');
+            }
+        }
+
+        // Paragraph breaks are the preferred place to start a new chunk
+        if current.len() > MAX_CHUNK_CHARS / 2 {
+            flush(&heading, &mut current);
+        } else if !current.is_empty() {
+            current.push('\n');
+        }
+    }
+    flush(&heading, &mut current);
+
+    chunks
+}
+
+// The title of a markdown heading like "## Voice channels". "#general" and the like are not headings.
+fn heading_title(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let title = line.trim_start_matches('#');
+    let level = line.len() - title.len();
+    ((1..=6).contains(&level) && title.starts_with(' ')).then(|| title.trim())
+}
+
+// Splits a line into pieces of at most `limit` bytes, preferring to break between words
+fn split_line(mut line: &str, limit: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    while line.len() > limit {
+        let mut end = limit;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        if let Some(space) = line[..end]
+            .rfind(char::is_whitespace)
+            .filter(|&space| space > 0)
+        {
+            end = space;
+        }
+        pieces.push(line[..end].trim_end());
+        line = line[end..].trim_start();
+    }
+    pieces.push(line);
+    pieces
+}
+
+fn load_index<'a>(
+    data: &DataDir,
+    indexes: &'a mut HashMap<u64, VectorIndex<KnowledgeChunk>>,
+    guild_id: u64,
+) -> &'a mut VectorIndex<KnowledgeChunk> {
+    indexes.entry(guild_id).or_insert_with(|| {
+        VectorIndex::load(
+            data.path("knowledge", &format!("{}.jsonl", guild_id)),
+            KNOWLEDGE_CAPACITY,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(heading: Option<&str>) -> KnowledgeChunk {
+        KnowledgeChunk {
+            document: "rules.md".to_string(),
+            heading: heading.map(str::to_string),
+            text: "Be nice.".to_string(),
+        }
+    }
+
+    #[test]
+    fn chunks_follow_markdown_headings() {
+        let content = "Welcome!\n\n# Voice channels\nNo music bots.\n#general is for chatting.\n\n## Bans\n#not a heading either";
+        let chunks = chunk_document("rules.md", content);
+
+        let chunks: Vec<(Option<&str>, &str)> = chunks
+            .iter()
+            .map(|chunk| (chunk.heading.as_deref(), chunk.text.as_str()))
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                (None, "Welcome!"),
+                (
+                    Some("Voice channels"),
+                    "No music bots.\n#general is for chatting."
+                ),
+                (Some("Bans"), "#not a heading either"),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split_between_words() {
+        let line = "word ".repeat(MAX_CHUNK_CHARS);
+        let chunks = chunk_document(
+            "rules.md",
+            &format!("{}\n{}", line, "é".repeat(MAX_CHUNK_CHARS)),
+        );
+
+        assert!(chunks.len() >= 7);
+        for chunk in &chunks {
+            assert!(chunk.text.len() <= MAX_CHUNK_CHARS);
+            assert!(!chunk.text.contains("wo\n") && !chunk.text.starts_with("rd"));
+        }
+        let words: usize = chunks
+            .iter()
+            .map(|chunk| chunk.text.matches("word").count())
+            .sum();
+        assert_eq!(words, MAX_CHUNK_CHARS);
+    }
+
+    #[test]
+    fn only_cited_sources_are_listed() {
+        let chunks = [
+            chunk(Some("Voice channels")),
+            chunk(None),
+            chunk(Some("Bans")),
+        ];
+
+        assert_eq!(append_citations("No idea.", &chunks), "No idea.");
+        assert_eq!(
+            append_citations("No music bots [1], no spam [3].", &chunks),
+            "No music bots [1], no spam [3].\n\n*Sources: [1] rules.md › Voice channels, [3] rules.md › Bans*"
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use whatlang::Lang;
+
+// Very short messages ("ok", "lol") are too ambiguous to tell the language from
+const MIN_DETECTION_CHARS: usize = 12;
+
+// The language a message is written in, when it can be told reliably
+pub fn detect_language(text: &str) -> Option<Lang> {
+    if text.trim().chars().count() < MIN_DETECTION_CHARS {
+        return None;
+    }
+
+    whatlang::detect(text)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang())
+}
+
+// Parses an ISO 639-3 code ("deu") or a language name in English or in the language itself ("German", "Deutsch")
+pub fn parse_language(argument: &str) -> Option<Lang> {
+    let argument = argument.trim().to_lowercase();
+
+    Lang::from_code(argument.as_str()).or_else(|| {
+        Lang::all().iter().copied().find(|lang| {
+            lang.eng_name().to_lowercase() == argument || lang.name().to_lowercase() == argument
+        })
+    })
+}
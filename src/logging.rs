@@ -0,0 +1,84 @@
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::EnvFilter;
+
+// Whether message content, prompts and replies may end up in the logs, off unless LOG_CONTENT is set
+static LOG_CONTENT: AtomicBool = AtomicBool::new(false);
+
+// Sets up tracing from the environment:
+// LOG_LEVEL (or RUST_LOG) takes a level or filter like "info,discord_gpt=debug", defaults to info.
+// LOG_FORMAT=json writes one JSON object per line, anything else human readable text.
+// LOG_CONTENT=true logs what users wrote and what the bot answered, for debugging only.
+pub fn init() {
+    let filter = env::var("LOG_LEVEL")
+        .or_else(|_| env::var("RUST_LOG"))
+        .unwrap_or_else(|_| "info".to_string());
+    let filter = EnvFilter::try_new(&filter).unwrap_or_else(|e| {
+        eprintln!("Invalid LOG_LEVEL {}, using info: {}", filter, e);
+        EnvFilter::new("info")
+    });
+
+    let content = env::var("LOG_CONTENT").is_ok_and(|value| is_enabled(&value));
+    LOG_CONTENT.store(content, Ordering::Relaxed);
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let format = env::var("LOG_FORMAT").unwrap_or_default();
+    if format.eq_ignore_ascii_case("json") {
+        builder.json().with_current_span(true).init();
+    } else {
+        builder.init();
+    }
+    if content {
+        tracing::warn!("LOG_CONTENT is set, message content will be logged");
+    }
+}
+
+fn is_enabled(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+// User content for a log line, only its length unless LOG_CONTENT is set
+pub fn content(text: &str) -> Content<'_> {
+    Content {
+        text,
+        visible: LOG_CONTENT.load(Ordering::Relaxed),
+    }
+}
+
+pub struct Content<'a> {
+    text: &'a str,
+    visible: bool,
+}
+
+impl fmt::Display for Content<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.visible {
+            write!(f, "{:?}", self.text)
+        } else {
+            write!(f, "[{} chars redacted]", self.text.chars().count())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_is_redacted_unless_enabled() {
+        let hidden = Content {
+            text: "my secret",
+            visible: false,
+        };
+        assert_eq!(hidden.to_string(), "[9 chars redacted]");
+
+        let shown = Content {
+            text: "my secret",
+            visible: true,
+        };
+        assert_eq!(shown.to_string(), "\"my secret\"");
+        assert!(is_enabled("TRUE"));
+        assert!(!is_enabled("false"));
+    }
+}
@@ -1,23 +1,82 @@
 use chatgpt::prelude::*;
 
+mod abuse;
+mod access_control;
+mod chat_client;
+mod commands;
+mod conversation;
+mod embeddings;
 mod event_handler;
+mod guild_settings;
 mod handler;
+mod health;
+mod injection;
+mod knowledge_base;
+mod language;
+mod logging;
+mod message_queue;
+mod metrics;
+mod moderation;
+mod mood;
+mod permissions;
+mod preset_router;
 mod preset_selection;
+mod prompt;
+mod semantic_memory;
 mod sentiment_analysis;
+mod server;
+mod storage;
+mod summarize;
+mod template;
+mod tone;
+mod user_memory;
+mod vector_index;
 
 use serenity::Client;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::error;
 // use handler::Handler;
 
 #[tokio::main]
 async fn main() {
+    logging::init();
+
     // Read the bot discord from an environment variable
     let discord = std::env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
     let chatgpt = std::env::var("OPENAI_API_KEY").expect("Expected a discord in the environment");
 
     // Instantiating a new ChatGPT client using the provided chatgpt model
     // Creating a new Handler object that uses the ChatGPT client
+    let chat_client = std::sync::Arc::new(chat_client::ChatClient::from_env(chatgpt.clone()));
+    let moderation = moderation::ModerationPipeline::from_env(chatgpt.clone());
     let client = ChatGPT::new(chatgpt).unwrap();
-    let handler = handler::Handler::new_chatbot(client).await;
+    preset_selection::validate_presets().expect("Invalid preset");
+    let data = storage::DataDir::from_env();
+    let embedder = embeddings::embedder_from_env();
+    let memory = semantic_memory::SemanticMemory::new(embedder.clone(), data.clone());
+    let knowledge = knowledge_base::KnowledgeBase::new(embedder.clone(), data.clone());
+    let router = preset_router::PresetRouter::from_env(embedder, client.clone());
+    let sentiment = sentiment_analysis::MultilingualSentiment::from_env(client.clone());
+    let injection = injection::InjectionDetector::from_env(client.clone());
+    let handler = handler::Handler::new_chatbot(
+        client,
+        chat_client,
+        data,
+        memory,
+        knowledge,
+        router,
+        sentiment,
+        injection,
+        moderation,
+    )
+    .await;
+
+    if let Some(address) = server::address_from_env() {
+        tokio::spawn(server::serve(address, handler.health.clone()));
+    }
+
+    let shutdown_handler = handler.clone();
 
     // Create a client using the bot discord and the Handler struct
     let mut client = Client::builder(discord)
@@ -25,8 +84,33 @@ async fn main() {
         .await
         .expect("Error creating client");
 
+    // On SIGTERM or Ctrl-C, answer what is queued, then disconnect so the client below returns
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_handler.shutdown(shutdown_timeout()).await;
+        shard_manager.lock().await.shutdown_all().await;
+    });
+
     // Start the client
     if let Err(why) = client.start().await {
-        println!("An error occurred while running the client: {:?}", why);
+        error!("An error occurred while running the client: {:?}", why);
     }
 }
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+// Reads SHUTDOWN_TIMEOUT, how many seconds a shutdown waits for queued messages, 30 by default
+fn shutdown_timeout() -> Duration {
+    let seconds = std::env::var("SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
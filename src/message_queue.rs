@@ -0,0 +1,398 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+use tracing::{error, info, warn};
+
+use crate::handler::QueuedMessage;
+use crate::metrics::METRICS;
+use crate::storage::DataDir;
+
+// A message that was handed out this many times without being answered, say because it
+// crashed the worker every time, is given up on
+const MAX_ATTEMPTS: u32 = 3;
+// Ids of answered messages remembered to ignore them if Discord or a replay delivers them again
+const ANSWERED_IDS: usize = 1000;
+
+// What happens to a new message when the queue is full
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    // The oldest waiting message is dropped to make room
+    DropOldest,
+    // The new message is turned away and its author told to try again later
+    Reject,
+}
+
+impl OverflowPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace('_', "-").as_str() {
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "reject" => Some(OverflowPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+pub enum Pushed {
+    Queued,
+    // The message is already queued or was answered before
+    Duplicate,
+    DroppedOldest(QueuedMessage),
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    message: QueuedMessage,
+    attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    // Oldest first
+    waiting: VecDeque<Entry>,
+    // Handed to the worker but not acknowledged yet, replayed if the bot stops before they are
+    in_flight: Vec<Entry>,
+    answered: VecDeque<u64>,
+    #[serde(skip)]
+    version: u64,
+}
+
+impl State {
+    fn contains(&self, message_id: u64) -> bool {
+        self.answered.contains(&message_id)
+            || self
+                .waiting
+                .iter()
+                .chain(&self.in_flight)
+                .any(|entry| entry.message.message_id == message_id)
+    }
+
+    fn answered(&mut self, message_id: u64) {
+        self.answered.push_back(message_id);
+        if self.answered.len() > ANSWERED_IDS {
+            self.answered.pop_front();
+        }
+    }
+}
+
+// Messages waiting to be answered, written to disk on every change so none are lost when the bot stops.
+// Every message is handed out until it is acknowledged, so it is answered at least once.
+pub struct MessageQueue {
+    state: Mutex<State>,
+    available: Notify,
+    file: Arc<QueueFile>,
+    capacity: usize,
+    overflow: OverflowPolicy,
+}
+
+// A version of the queue to write to disk, numbered in the order the changes were made
+struct Snapshot {
+    version: u64,
+    json: String,
+}
+
+// Where the queue is saved. Snapshots are written from a blocking thread, off the gateway and worker tasks.
+struct QueueFile {
+    path: PathBuf,
+    // Version of the last snapshot written, so one that finishes late never overwrites a newer one
+    written: std::sync::Mutex<u64>,
+}
+
+impl QueueFile {
+    fn write(&self, snapshot: Snapshot) -> std::io::Result<()> {
+        let mut written = self.written.lock().unwrap();
+        if snapshot.version <= *written {
+            return Ok(());
+        }
+
+        // Synced to a temporary file first, so a crash never leaves half a queue behind
+        let temporary = self.path.with_extension("json.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(snapshot.json.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+
+        *written = snapshot.version;
+        Ok(())
+    }
+}
+
+impl MessageQueue {
+    // Loads the messages left over from the last run, they are answered first
+    pub fn open(path: PathBuf, capacity: usize, overflow: OverflowPolicy) -> Self {
+        let mut state: State = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Failed to read the message queue from {:?}: {}", path, e);
+                State::default()
+            }),
+            Err(_) => State::default(),
+        };
+
+        for entry in state.in_flight.drain(..).rev() {
+            state.waiting.push_front(entry);
+        }
+        if !state.waiting.is_empty() {
+            info!(
+                "Replaying {} messages left in the queue",
+                state.waiting.len()
+            );
+        }
+        METRICS.queue_length.set(state.waiting.len() as i64);
+
+        let queue = MessageQueue {
+            state: Mutex::new(state),
+            available: Notify::new(),
+            file: Arc::new(QueueFile {
+                path,
+                written: std::sync::Mutex::new(0),
+            }),
+            capacity,
+            overflow,
+        };
+        queue.available.notify_one();
+        queue
+    }
+
+    // Reads QUEUE_CAPACITY, 100 by default, and QUEUE_OVERFLOW, "reject" (default) or "drop-oldest"
+    pub fn from_env(data: &DataDir) -> Self {
+        let capacity = std::env::var("QUEUE_CAPACITY")
+            .ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .unwrap_or(100);
+        let overflow = std::env::var("QUEUE_OVERFLOW").unwrap_or_default();
+        let overflow = OverflowPolicy::parse(&overflow).unwrap_or_else(|| {
+            if !overflow.is_empty() {
+                warn!("Unknown QUEUE_OVERFLOW {}, rejecting messages", overflow);
+            }
+            OverflowPolicy::Reject
+        });
+
+        MessageQueue::open(data.path("queue", "queue.json"), capacity, overflow)
+    }
+
+    pub async fn push(&self, message: QueuedMessage) -> Pushed {
+        let mut state = self.state.lock().await;
+        if state.contains(message.message_id) {
+            return Pushed::Duplicate;
+        }
+
+        let mut pushed = Pushed::Queued;
+        if state.waiting.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::Reject => return Pushed::Rejected,
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = state.waiting.pop_front() {
+                        pushed = Pushed::DroppedOldest(oldest.message);
+                    }
+                }
+            }
+        }
+
+        state.waiting.push_back(Entry {
+            message,
+            attempts: 0,
+        });
+        let snapshot = snapshot(&mut state);
+        drop(state);
+
+        self.available.notify_one();
+        self.save(snapshot).await;
+        pushed
+    }
+
+    // Waits for the next message, which stays in the queue until it is acknowledged
+    pub async fn next(&self) -> QueuedMessage {
+        loop {
+            let mut state = self.state.lock().await;
+            while let Some(mut entry) = state.waiting.pop_front() {
+                // A worker that was given up on can still finish the message after it was put back
+                if state.answered.contains(&entry.message.message_id) {
+                    continue;
+                }
+                if entry.attempts >= MAX_ATTEMPTS {
+                    error!(
+                        "Giving up on message {} after {} attempts",
+                        entry.message.message_id, entry.attempts
+                    );
+                    METRICS.dropped("failed");
+                    state.answered(entry.message.message_id);
+                    continue;
+                }
+
+                if entry.attempts > 0 {
+                    METRICS.message_retries.inc();
+                }
+                entry.attempts += 1;
+                let message = entry.message.clone();
+                state.in_flight.push(entry);
+                let snapshot = snapshot(&mut state);
+                drop(state);
+
+                self.save(snapshot).await;
+                return message;
+            }
+            drop(state);
+            self.available.notified().await;
+        }
+    }
+
+    pub async fn ack(&self, message_id: u64) {
+        let mut state = self.state.lock().await;
+        state
+            .in_flight
+            .retain(|entry| entry.message.message_id != message_id);
+        state.answered(message_id);
+        let snapshot = snapshot(&mut state);
+        drop(state);
+
+        self.save(snapshot).await;
+    }
+
+    // Puts messages a dead worker was answering back in front, for the worker replacing it
+    pub async fn requeue_in_flight(&self) {
+        let mut state = self.state.lock().await;
+        if state.in_flight.is_empty() {
+            return;
+        }
+        let in_flight: Vec<Entry> = state.in_flight.drain(..).collect();
+        for entry in in_flight.into_iter().rev() {
+            state.waiting.push_front(entry);
+        }
+        let snapshot = snapshot(&mut state);
+        drop(state);
+
+        self.available.notify_one();
+        self.save(snapshot).await;
+    }
+
+    // Messages waiting or being answered
+    pub async fn len(&self) -> usize {
+        let state = self.state.lock().await;
+        state.waiting.len() + state.in_flight.len()
+    }
+
+    // Writes a snapshot without holding up other tasks, returns once it, or a newer one, is on disk
+    async fn save(&self, snapshot: Snapshot) {
+        let file = self.file.clone();
+        let result = tokio::task::spawn_blocking(move || file.write(snapshot))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|written| written);
+
+        if let Err(e) = result {
+            error!(
+                "Failed to save the message queue to {:?}: {}",
+                self.file.path, e
+            );
+        }
+    }
+}
+
+// Taken while the state is locked, so snapshots are numbered in the order of the changes
+fn snapshot(state: &mut State) -> Snapshot {
+    METRICS.queue_length.set(state.waiting.len() as i64);
+    state.version += 1;
+    Snapshot {
+        version: state.version,
+        json: serde_json::to_string(state).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_id: u64) -> QueuedMessage {
+        QueuedMessage {
+            message_id,
+            ..QueuedMessage::test(1, &format!("message {}", message_id))
+        }
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_messages_are_replayed_once_reopened() {
+        let data = DataDir::temporary();
+        let path = data.path("queue", "replay.json");
+        let queue = MessageQueue::open(path.clone(), 10, OverflowPolicy::Reject);
+        for id in 1..=3 {
+            assert!(matches!(queue.push(message(id)).await, Pushed::Queued));
+        }
+        assert!(matches!(queue.push(message(2)).await, Pushed::Duplicate));
+
+        let first = queue.next().await;
+        queue.ack(first.message_id).await;
+        // The bot stops while answering the second message
+        assert_eq!(queue.next().await.message_id, 2);
+
+        let queue = MessageQueue::open(path, 10, OverflowPolicy::Reject);
+        assert_eq!(queue.len().await, 2);
+        assert_eq!(queue.next().await.message_id, 2);
+        assert_eq!(queue.next().await.message_id, 3);
+        assert!(matches!(queue.push(message(1)).await, Pushed::Duplicate));
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_the_oldest_or_rejects() {
+        let data = DataDir::temporary();
+        let queue = MessageQueue::open(
+            data.path("queue", "drop.json"),
+            2,
+            OverflowPolicy::DropOldest,
+        );
+        queue.push(message(1)).await;
+        queue.push(message(2)).await;
+        match queue.push(message(3)).await {
+            Pushed::DroppedOldest(dropped) => assert_eq!(dropped.message_id, 1),
+            _ => panic!("expected the oldest message to be dropped"),
+        }
+        assert_eq!(queue.next().await.message_id, 2);
+
+        let queue =
+            MessageQueue::open(data.path("queue", "reject.json"), 1, OverflowPolicy::Reject);
+        queue.push(message(1)).await;
+        assert!(matches!(queue.push(message(2)).await, Pushed::Rejected));
+    }
+
+    #[tokio::test]
+    async fn messages_crashing_the_worker_are_given_up_on() {
+        let data = DataDir::temporary();
+        let queue = MessageQueue::open(
+            data.path("queue", "attempts.json"),
+            10,
+            OverflowPolicy::Reject,
+        );
+        queue.push(message(1)).await;
+        queue.push(message(2)).await;
+        let retries = METRICS.message_retries.get();
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(queue.next().await.message_id, 1);
+            queue.requeue_in_flight().await;
+        }
+        assert_eq!(queue.next().await.message_id, 2);
+        // Other tests retry messages too, at the same time
+        assert!(METRICS.message_retries.get() >= retries + MAX_ATTEMPTS as u64 - 1);
+    }
+
+    #[tokio::test]
+    async fn answered_messages_are_not_handed_out_again() {
+        let data = DataDir::temporary();
+        let queue = MessageQueue::open(
+            data.path("queue", "answered.json"),
+            10,
+            OverflowPolicy::Reject,
+        );
+        queue.push(message(1)).await;
+        assert_eq!(queue.next().await.message_id, 1);
+
+        // The worker is given up on, but still finishes the message after it was put back
+        queue.requeue_in_flight().await;
+        queue.ack(1).await;
+        queue.push(message(2)).await;
+        assert_eq!(queue.next().await.message_id, 2);
+        assert_eq!(queue.len().await, 1);
+    }
+}
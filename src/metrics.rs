@@ -0,0 +1,149 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+// Everything the bot counts, served in the Prometheus text format on /metrics
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: IntCounter,
+    pub messages_responded: IntCounter,
+    // Messages addressed to the bot that never got an answer, by reason
+    pub messages_dropped: IntCounterVec,
+    pub queue_length: IntGauge,
+    pub backend_latency: HistogramVec,
+    // Queued messages handed out again after an attempt that didn't finish
+    pub message_retries: IntCounter,
+    // Prompt and completion tokens spent, by model
+    pub tokens: IntCounterVec,
+    // Presets picked by the router for new conversations, by preset and routing method
+    pub preset_selections: IntCounterVec,
+    pub moderation_actions: IntCounterVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            messages_received: IntCounter::new(
+                "discord_messages_received_total",
+                "Messages seen by the bot, its own excluded",
+            )
+            .unwrap(),
+            messages_responded: IntCounter::new(
+                "discord_messages_responded_total",
+                "Replies sent to a channel",
+            )
+            .unwrap(),
+            messages_dropped: IntCounterVec::new(
+                Opts::new(
+                    "discord_messages_dropped_total",
+                    "Messages to the bot that were not answered",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            queue_length: IntGauge::new(
+                "discord_queue_length",
+                "Messages waiting in the queue to be answered",
+            )
+            .unwrap(),
+            backend_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "backend_request_duration_seconds",
+                    "Time taken by requests to the model",
+                )
+                .buckets(vec![0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]),
+                &["model"],
+            )
+            .unwrap(),
+            message_retries: IntCounter::new(
+                "discord_message_retries_total",
+                "Queued messages retried after the worker failed to answer them",
+            )
+            .unwrap(),
+            tokens: IntCounterVec::new(
+                Opts::new(
+                    "backend_tokens_total",
+                    "Tokens used by requests to the model",
+                ),
+                &["model", "kind"],
+            )
+            .unwrap(),
+            preset_selections: IntCounterVec::new(
+                Opts::new(
+                    "preset_selections_total",
+                    "Presets picked for new conversations",
+                ),
+                &["preset", "method"],
+            )
+            .unwrap(),
+            moderation_actions: IntCounterVec::new(
+                Opts::new(
+                    "moderation_actions_total",
+                    "Messages and replies flagged by moderation, by the action taken",
+                ),
+                &["action"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.messages_received.clone()),
+            Box::new(metrics.messages_responded.clone()),
+            Box::new(metrics.messages_dropped.clone()),
+            Box::new(metrics.queue_length.clone()),
+            Box::new(metrics.backend_latency.clone()),
+            Box::new(metrics.message_retries.clone()),
+            Box::new(metrics.tokens.clone()),
+            Box::new(metrics.preset_selections.clone()),
+            Box::new(metrics.moderation_actions.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn dropped(&self, reason: &str) {
+        self.messages_dropped.with_label_values(&[reason]).inc();
+    }
+
+    // Counts the tokens reported in a completion's usage
+    pub fn record_tokens(&self, model: &str, prompt: u64, completion: u64) {
+        self.tokens
+            .with_label_values(&[model, "prompt"])
+            .inc_by(prompt);
+        self.tokens
+            .with_label_values(&[model, "completion"])
+            .inc_by(completion);
+    }
+
+    // All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_labelled_metrics() {
+        METRICS.dropped("spam");
+        METRICS.record_tokens("test-model", 12, 3);
+
+        let rendered = METRICS.render();
+        assert!(rendered.contains("discord_messages_dropped_total{reason=\"spam\"}"));
+        assert!(rendered.contains("backend_tokens_total{kind=\"prompt\",model=\"test-model\"} 12"));
+    }
+}
@@ -0,0 +1,506 @@
+use chatgpt::err::Error;
+use chatgpt::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serenity::async_trait;
+use std::ops::Range;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::metrics::METRICS;
+
+// Sent instead of a reply when the message it answers was blocked
+pub const BLOCKED_MESSAGE: &str = "I can't respond to that message.";
+// Sent instead of a reply that was blocked
+pub const WITHHELD_REPLY: &str = "I started writing a reply, but it was held back by moderation.";
+const REDACTION: &str = "[redacted]";
+
+// What a moderator found in a piece of text. Nothing was found when there are no categories.
+#[derive(Default)]
+pub struct Moderation {
+    pub categories: Vec<String>,
+    // Byte ranges of the flagged parts
+    pub spans: Vec<Range<usize>>,
+    // Set when a moderator flagged the text as a whole without saying which parts, whatever others found
+    pub whole_text: bool,
+}
+
+impl Moderation {
+    pub fn flagged(&self) -> bool {
+        !self.categories.is_empty()
+    }
+
+    // Replaces the flagged parts, or the whole text when a moderator didn't say which parts were flagged
+    pub fn redact(&self, text: &str) -> String {
+        if self.whole_text || self.spans.is_empty() {
+            return REDACTION.to_string();
+        }
+
+        let mut spans = self.spans.clone();
+        spans.sort_by_key(|span| span.start);
+
+        let mut redacted = String::with_capacity(text.len());
+        let mut rest = 0;
+        for span in spans {
+            // Overlapping spans were already covered by the previous one
+            if span.end <= rest {
+                continue;
+            }
+            redacted.push_str(&text[rest..span.start.max(rest)]);
+            redacted.push_str(REDACTION);
+            rest = span.end;
+        }
+        redacted.push_str(&text[rest..]);
+        redacted
+    }
+
+    fn merge(&mut self, other: Moderation) {
+        self.whole_text |= other.whole_text || (other.flagged() && other.spans.is_empty());
+        for category in other.categories {
+            if !self.categories.contains(&category) {
+                self.categories.push(category);
+            }
+        }
+        self.spans.extend(other.spans);
+    }
+}
+
+#[async_trait]
+pub trait Moderator: Send + Sync {
+    async fn check(&self, text: &str) -> Result<Moderation>;
+}
+
+// Uses the OpenAI moderation endpoint, which flags a text as a whole
+pub struct OpenAIModerator {
+    http: reqwest::Client,
+    api_key: String,
+}
+
+impl OpenAIModerator {
+    pub fn new(api_key: String) -> Self {
+        OpenAIModerator {
+            http: reqwest::Client::new(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl Moderator for OpenAIModerator {
+    async fn check(&self, text: &str) -> Result<Moderation> {
+        let response: Value = self
+            .http
+            .post("https://api.openai.com/v1/moderations")
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "input": text }))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::BackendError {
+                message: error["message"].as_str().unwrap_or_default().to_string(),
+                error_type: error["type"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+
+        let categories = response["results"][0]["categories"]
+            .as_object()
+            .ok_or_else(|| {
+                Error::ParsingError(format!("Unexpected moderation response: {}", response))
+            })?;
+
+        let categories: Vec<String> = categories
+            .iter()
+            .filter(|(_, flagged)| flagged.as_bool().unwrap_or(false))
+            .map(|(category, _)| category.clone())
+            .collect();
+        Ok(Moderation {
+            whole_text: !categories.is_empty(),
+            categories,
+            spans: Vec::new(),
+        })
+    }
+}
+
+// Local rules, one per line of a rules file:
+//   word or phrase            matched case-insensitively as whole words
+//   /regular expression/      matched as written
+// Either can start with a [category], otherwise the category is "wordlist". Lines starting with # are comments.
+pub struct RuleModerator {
+    rules: Vec<(String, Regex)>,
+}
+
+impl RuleModerator {
+    pub fn parse(source: &str) -> std::result::Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (category, pattern) =
+                match line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+                    Some((category, pattern)) => (category.trim().to_string(), pattern.trim()),
+                    None => ("wordlist".to_string(), line),
+                };
+
+            let regex = match pattern
+                .strip_prefix('/')
+                .and_then(|rest| rest.strip_suffix('/'))
+            {
+                Some(regex) => regex.to_string(),
+                None => format!(r"(?i)\b{}\b", regex::escape(pattern)),
+            };
+            let regex = Regex::new(&regex)
+                .map_err(|e| format!("Invalid moderation rule on line {}: {}", number + 1, e))?;
+            rules.push((category, regex));
+        }
+
+        Ok(RuleModerator { rules })
+    }
+}
+
+#[async_trait]
+impl Moderator for RuleModerator {
+    async fn check(&self, text: &str) -> Result<Moderation> {
+        let mut moderation = Moderation::default();
+        for (category, regex) in &self.rules {
+            let spans: Vec<Range<usize>> =
+                regex.find_iter(text).map(|found| found.range()).collect();
+            if !spans.is_empty() {
+                moderation.merge(Moderation {
+                    categories: vec![category.clone()],
+                    spans,
+                    whole_text: false,
+                });
+            }
+        }
+        Ok(moderation)
+    }
+}
+
+// Flags every text containing one of its terms, for tests
+#[cfg(test)]
+pub struct MockModerator {
+    terms: Vec<String>,
+}
+
+#[cfg(test)]
+impl MockModerator {
+    pub fn new(terms: &[&str]) -> Self {
+        MockModerator {
+            terms: terms.iter().map(|term| term.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Moderator for MockModerator {
+    async fn check(&self, text: &str) -> Result<Moderation> {
+        let spans: Vec<Range<usize>> = self
+            .terms
+            .iter()
+            .flat_map(|term| {
+                text.match_indices(term.as_str())
+                    .map(move |(start, _)| start..start + term.len())
+            })
+            .collect();
+
+        if spans.is_empty() {
+            return Ok(Moderation::default());
+        }
+        Ok(Moderation {
+            categories: vec!["mock".to_string()],
+            spans,
+            whole_text: false,
+        })
+    }
+}
+
+// What happens to flagged content, set per guild for incoming messages and outgoing replies
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ModerationAction {
+    // Flagged messages are not answered, flagged replies are not posted
+    Block,
+    // The flagged parts are replaced before the content goes any further
+    Redact,
+    // The content goes through, and the reply says what was flagged
+    Warn,
+}
+
+impl std::fmt::Display for ModerationAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ModerationAction::Block => write!(f, "block"),
+            ModerationAction::Redact => write!(f, "redact"),
+            ModerationAction::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+impl ModerationAction {
+    pub fn parse(name: &str) -> Option<ModerationAction> {
+        match name.to_lowercase().as_str() {
+            "block" => Some(ModerationAction::Block),
+            "redact" => Some(ModerationAction::Redact),
+            "warn" => Some(ModerationAction::Warn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ModerationSettings {
+    pub input: ModerationAction,
+    pub output: ModerationAction,
+}
+
+// Users' messages are redacted, the bot's own replies are held back entirely
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        ModerationSettings {
+            input: ModerationAction::Redact,
+            output: ModerationAction::Block,
+        }
+    }
+}
+
+pub enum Outcome {
+    Allowed,
+    Blocked,
+    Redacted(String),
+    // The content is allowed, with the categories it was flagged for
+    Warned(Vec<String>),
+}
+
+// Runs every configured moderator over a text and merges what they found.
+// A moderator that fails is skipped, so an outage doesn't stop the bot from answering.
+#[derive(Default)]
+pub struct ModerationPipeline {
+    moderators: Vec<Arc<dyn Moderator>>,
+}
+
+impl ModerationPipeline {
+    pub fn new(moderators: Vec<Arc<dyn Moderator>>) -> Self {
+        ModerationPipeline { moderators }
+    }
+
+    // Reads MODERATION, a comma separated list of "rules" (default) and "openai",
+    // and MODERATION_RULES, the path of the rules file
+    pub fn from_env(api_key: String) -> Self {
+        let names = std::env::var("MODERATION").unwrap_or_else(|_| "rules".to_string());
+        let mut moderators: Vec<Arc<dyn Moderator>> = Vec::new();
+
+        for name in names.split(',').map(|name| name.trim().to_lowercase()) {
+            match name.as_str() {
+                "openai" => moderators.push(Arc::new(OpenAIModerator::new(api_key.clone()))),
+                "rules" => match std::env::var("MODERATION_RULES") {
+                    Ok(path) => {
+                        let source = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+                            panic!("Failed to read moderation rules from {}: {}", path, e)
+                        });
+                        moderators.push(Arc::new(RuleModerator::parse(&source).unwrap()));
+                    }
+                    Err(_) => info!("No MODERATION_RULES file set, skipping rule moderation"),
+                },
+                "" | "none" => {}
+                _ => warn!("Unknown moderator {}, skipping it", name),
+            }
+        }
+
+        ModerationPipeline::new(moderators)
+    }
+
+    pub async fn check(&self, text: &str) -> Moderation {
+        let mut moderation = Moderation::default();
+        for moderator in &self.moderators {
+            match moderator.check(text).await {
+                Ok(found) => moderation.merge(found),
+                Err(e) => warn!("Moderation check failed: {}", e),
+            }
+        }
+        moderation
+    }
+
+    // Checks a text and applies the guild's action to it. `what` describes the text in the log.
+    pub async fn apply(&self, text: &str, action: ModerationAction, what: &str) -> Outcome {
+        let moderation = self.check(text).await;
+        if !moderation.flagged() {
+            return Outcome::Allowed;
+        }
+
+        warn!(
+            "Moderation flagged {} ({}), action: {}",
+            what,
+            moderation.categories.join(", "),
+            action
+        );
+        METRICS
+            .moderation_actions
+            .with_label_values(&[&action.to_string()])
+            .inc();
+        match action {
+            ModerationAction::Block => Outcome::Blocked,
+            ModerationAction::Redact => Outcome::Redacted(moderation.redact(text)),
+            ModerationAction::Warn => Outcome::Warned(moderation.categories),
+        }
+    }
+}
+
+// Put in front of replies when something was let through with a warning
+pub fn warning(categories: &[String]) -> String {
+    format!("⚠️ Flagged by moderation: {}", categories.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::MockBackend;
+    use crate::handler::{Handler, QueuedMessage};
+    use chatgpt::types::Role;
+
+    async fn handler(
+        backend: Arc<MockBackend>,
+        guild_id: u64,
+        settings: ModerationSettings,
+    ) -> Handler {
+        let mut handler = Handler::with_backend(backend).await;
+        handler.moderation = Arc::new(ModerationPipeline::new(vec![Arc::new(MockModerator::new(
+            &["badword"],
+        ))]));
+        handler
+            .settings
+            .update(guild_id, |guild| guild.moderation = settings)
+            .await;
+        handler
+    }
+
+    #[tokio::test]
+    async fn rules_redact_whole_words_and_regexes() {
+        let rules = RuleModerator::parse(
+            "# comment\nfrack\n[spam] /(?i)free\\s+nitro/\n[insult] dumb bot\n",
+        )
+        .unwrap();
+
+        let text = "Fracking is fine, but frack you. FREE   nitro here, dumb bot";
+        let moderation = rules.check(text).await.unwrap();
+        assert_eq!(moderation.categories, ["wordlist", "spam", "insult"]);
+        assert_eq!(
+            moderation.redact(text),
+            "Fracking is fine, but [redacted] you. [redacted] here, [redacted]"
+        );
+        assert!(RuleModerator::parse("/(unclosed/").is_err());
+    }
+
+    // Flags texts mentioning a threat as a whole, like the OpenAI moderator
+    struct WholeTextModerator;
+
+    #[async_trait]
+    impl Moderator for WholeTextModerator {
+        async fn check(&self, text: &str) -> Result<Moderation> {
+            let flagged = text.contains("threat");
+            Ok(Moderation {
+                categories: if flagged {
+                    vec!["violence".to_string()]
+                } else {
+                    Vec::new()
+                },
+                spans: Vec::new(),
+                whole_text: flagged,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn text_flagged_as_a_whole_is_redacted_entirely_alongside_rule_spans() {
+        let pipeline = ModerationPipeline::new(vec![
+            Arc::new(RuleModerator::parse("badword").unwrap()),
+            Arc::new(WholeTextModerator),
+        ]);
+
+        let text = "badword, and a threat";
+        let moderation = pipeline.check(text).await;
+        assert_eq!(moderation.categories, ["wordlist", "violence"]);
+        assert_eq!(moderation.redact(text), REDACTION);
+
+        // Without the whole-text flag only the rule's span goes
+        let text = "badword, nothing else";
+        assert_eq!(
+            pipeline.check(text).await.redact(text),
+            "[redacted], nothing else"
+        );
+    }
+
+    #[tokio::test]
+    async fn blocked_messages_never_reach_the_backend() {
+        let backend = Arc::new(MockBackend::new("ok"));
+        let settings = ModerationSettings {
+            input: ModerationAction::Block,
+            output: ModerationAction::Block,
+        };
+        let handler = handler(backend.clone(), 4000, settings).await;
+
+        let reply = handler
+            .chatbot_response(&QueuedMessage::test(4000, "you badword"))
+            .await
+            .unwrap();
+        assert_eq!(reply, BLOCKED_MESSAGE);
+        assert!(backend.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn redacted_messages_and_replies_are_stored_redacted() {
+        let backend = Arc::new(MockBackend::new("sure, badword to you too"));
+        let settings = ModerationSettings {
+            input: ModerationAction::Redact,
+            output: ModerationAction::Redact,
+        };
+        let handler = handler(backend.clone(), 5000, settings).await;
+
+        let reply = handler
+            .chatbot_response(&QueuedMessage::test(5000, "hello badword"))
+            .await
+            .unwrap();
+        assert_eq!(reply, "sure, [redacted] to you too");
+
+        let sent = backend.requests.lock().unwrap()[0]
+            .last()
+            .unwrap()
+            .content
+            .clone();
+        assert_eq!(sent, "hello [redacted]");
+
+        let conversations = handler.conversations.lock().await;
+        let history = &conversations[&5001].0.history;
+        assert!(history.iter().all(|turn| !turn.content.contains("badword")));
+    }
+
+    #[tokio::test]
+    async fn blocked_replies_are_withheld_and_warnings_are_shown() {
+        let backend = Arc::new(MockBackend::new("badword"));
+        let settings = ModerationSettings {
+            input: ModerationAction::Warn,
+            output: ModerationAction::Block,
+        };
+        let handler = handler(backend.clone(), 6000, settings).await;
+
+        let reply = handler
+            .chatbot_response(&QueuedMessage::test(6000, "badword?"))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            format!("{}\n{}", warning(&["mock".to_string()]), WITHHELD_REPLY)
+        );
+
+        let conversations = handler.conversations.lock().await;
+        let last = conversations[&6001].0.history.last().unwrap();
+        assert!(last.role == Role::Assistant && last.content == WITHHELD_REPLY);
+    }
+}
@@ -0,0 +1,213 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use tokio::sync::Mutex;
+
+// Weight of the newest message in the moving average, higher reacts faster
+const SMOOTHING: f64 = 0.3;
+// How many past averages are kept to show the trajectory
+const TRAJECTORY_LENGTH: usize = 20;
+// A mood nobody added to for this long no longer says anything about the room or the person
+const MOOD_MEMORY_HOURS: i64 = 24;
+
+// Exponential moving average of sentiment scores, with its recent values
+#[derive(Clone, Default)]
+pub struct Mood {
+    pub average: f64,
+    pub trajectory: VecDeque<f64>,
+    updated: DateTime<Utc>,
+}
+
+impl Mood {
+    fn stale(&self, now: DateTime<Utc>) -> bool {
+        now - self.updated > Duration::hours(MOOD_MEMORY_HOURS)
+    }
+
+    fn record(&mut self, score: f64, now: DateTime<Utc>) {
+        if self.stale(now) {
+            self.trajectory.clear();
+        }
+        self.updated = now;
+
+        self.average = if self.trajectory.is_empty() {
+            score
+        } else {
+            SMOOTHING * score + (1.0 - SMOOTHING) * self.average
+        };
+
+        self.trajectory.push_back(self.average);
+        if self.trajectory.len() > TRAJECTORY_LENGTH {
+            self.trajectory.pop_front();
+        }
+    }
+
+    // The trajectory drawn with block characters, from -1 at the bottom to 1 at the top
+    pub fn sparkline(&self) -> String {
+        const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        self.trajectory
+            .iter()
+            .map(|score| {
+                let level = ((score + 1.0) / 2.0 * (BLOCKS.len() - 1) as f64).round();
+                BLOCKS[(level as usize).min(BLOCKS.len() - 1)]
+            })
+            .collect()
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self.average {
+            average if average >= 0.5 => "very positive",
+            average if average >= 0.15 => "positive",
+            average if average > -0.15 => "neutral",
+            average if average > -0.5 => "negative",
+            _ => "very negative",
+        }
+    }
+}
+
+// Rolling sentiment per channel and per user in each guild, None being direct messages
+#[derive(Default)]
+pub struct MoodTracker {
+    channels: Mutex<HashMap<u64, Mood>>,
+    users: Mutex<HashMap<(Option<u64>, u64), Mood>>,
+}
+
+impl MoodTracker {
+    pub async fn record(&self, guild_id: Option<u64>, channel_id: u64, user_id: u64, score: f64) {
+        self.record_at(guild_id, channel_id, user_id, score, Utc::now())
+            .await
+    }
+
+    async fn record_at(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        user_id: u64,
+        score: f64,
+        now: DateTime<Utc>,
+    ) {
+        record(&mut *self.channels.lock().await, channel_id, score, now);
+        record(
+            &mut *self.users.lock().await,
+            (guild_id, user_id),
+            score,
+            now,
+        );
+    }
+
+    // Channel moods are kept, they blend everyone's messages and no longer tell whose they were
+    pub async fn forget_user(&self, guild_id: Option<u64>, user_id: u64) {
+        self.users.lock().await.remove(&(guild_id, user_id));
+    }
+
+    pub async fn channel(&self, channel_id: u64) -> Option<Mood> {
+        let now = Utc::now();
+        self.channels
+            .lock()
+            .await
+            .get(&channel_id)
+            .filter(|mood| !mood.stale(now))
+            .cloned()
+    }
+
+    pub async fn user(&self, guild_id: Option<u64>, user_id: u64) -> Option<Mood> {
+        let now = Utc::now();
+        self.users
+            .lock()
+            .await
+            .get(&(guild_id, user_id))
+            .filter(|mood| !mood.stale(now))
+            .cloned()
+    }
+
+    // The score the tone should follow for a reply to this user: half the room, half the person
+    pub async fn blended(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        user_id: u64,
+    ) -> Option<f64> {
+        match (
+            self.channel(channel_id).await,
+            self.user(guild_id, user_id).await,
+        ) {
+            (Some(channel), Some(user)) => Some((channel.average + user.average) / 2.0),
+            (Some(mood), None) | (None, Some(mood)) => Some(mood.average),
+            (None, None) => None,
+        }
+    }
+}
+
+fn record<K: Eq + Hash>(moods: &mut HashMap<K, Mood>, key: K, score: f64, now: DateTime<Utc>) {
+    // Every channel and user ever seen would be kept otherwise, new ones are a good time to let go of stale ones
+    if !moods.contains_key(&key) {
+        moods.retain(|_, mood| !mood.stale(now));
+    }
+    moods
+        .entry(key)
+        .or_insert_with(|| Mood {
+            average: 0.0,
+            trajectory: VecDeque::new(),
+            updated: now,
+        })
+        .record(score, now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mood(scores: &[f64]) -> Mood {
+        let now = Utc::now();
+        let mut mood = Mood::default();
+        for score in scores {
+            mood.record(*score, now);
+        }
+        mood
+    }
+
+    #[test]
+    fn the_average_starts_at_the_first_score_and_moves_towards_new_ones() {
+        let recovering = mood(&[1.0, 0.0]);
+        assert_eq!(recovering.average, 0.7);
+        assert_eq!(recovering.label(), "very positive");
+
+        let upset = mood(&[-1.0; TRAJECTORY_LENGTH * 2]);
+        assert_eq!(upset.average, -1.0);
+        assert_eq!(upset.trajectory.len(), TRAJECTORY_LENGTH);
+        assert_eq!(upset.label(), "very negative");
+    }
+
+    #[test]
+    fn the_sparkline_goes_from_the_bottom_to_the_top() {
+        assert_eq!(mood(&[-1.0]).sparkline(), "▁");
+        assert_eq!(mood(&[1.0]).sparkline(), "█");
+        assert_eq!(mood(&[0.0, 1.0, 1.0]).sparkline(), "▅▆▆");
+        assert_eq!(Mood::default().sparkline(), "");
+    }
+
+    #[tokio::test]
+    async fn users_have_a_mood_per_guild_and_stale_moods_are_dropped() {
+        let tracker = MoodTracker::default();
+        let long_ago = Utc::now() - Duration::hours(MOOD_MEMORY_HOURS + 1);
+        tracker.record_at(Some(1), 10, 42, 1.0, long_ago).await;
+        assert!(tracker.user(Some(1), 42).await.is_none());
+        assert!(tracker.channel(10).await.is_none());
+        assert_eq!(tracker.users.lock().await.len(), 1);
+
+        // The stale mood goes as soon as someone new shows up
+        tracker.record(Some(2), 20, 42, -1.0).await;
+        assert_eq!(tracker.users.lock().await.len(), 1);
+        assert_eq!(tracker.user(Some(2), 42).await.unwrap().average, -1.0);
+        assert!(tracker.user(Some(1), 42).await.is_none());
+
+        // A stale channel mood starts over rather than averaging in the old scores
+        tracker.record(Some(1), 10, 42, -0.5).await;
+        assert_eq!(tracker.channel(10).await.unwrap().trajectory, [-0.5]);
+        assert_eq!(tracker.blended(Some(1), 10, 42).await, Some(-0.5));
+
+        tracker.forget_user(Some(1), 42).await;
+        assert!(tracker.user(Some(1), 42).await.is_none());
+        assert!(tracker.user(Some(2), 42).await.is_some());
+        assert!(tracker.channel(10).await.is_some());
+    }
+}
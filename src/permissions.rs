@@ -0,0 +1,81 @@
+use serenity::client::Context;
+use serenity::model::prelude::*;
+use std::collections::HashSet;
+use tracing::warn;
+
+use crate::handler::Handler;
+
+// What decides who administers a guild, cached so permission checks don't fetch the guild every time
+pub struct GuildAdmins {
+    owner_id: u64,
+    // Roles that can manage the server
+    admin_roles: HashSet<u64>,
+}
+
+impl GuildAdmins {
+    fn new(guild: &PartialGuild) -> Self {
+        GuildAdmins {
+            owner_id: guild.owner_id.0,
+            admin_roles: guild
+                .roles
+                .values()
+                .filter(|role| role.permissions.administrator() || role.permissions.manage_guild())
+                .map(|role| role.id.0)
+                .collect(),
+        }
+    }
+}
+
+impl Handler {
+    // Admins are the guild owner and anyone with a role that can manage the server
+    pub async fn is_admin(&self, ctx: &Context, msg: &Message) -> bool {
+        let (guild_id, member) = match (msg.guild_id, &msg.member) {
+            (Some(guild_id), Some(member)) => (guild_id, member),
+            _ => return false,
+        };
+
+        let mut guild_admins = self.guild_admins.lock().await;
+        let admins = match guild_admins.get(&guild_id.0) {
+            Some(admins) => admins,
+            None => match guild_id.to_partial_guild(&ctx.http).await {
+                Ok(guild) => guild_admins
+                    .entry(guild_id.0)
+                    .or_insert(GuildAdmins::new(&guild)),
+                Err(e) => {
+                    warn!(
+                        "Failed to fetch guild {} for a permission check: {}",
+                        guild_id, e
+                    );
+                    return false;
+                }
+            },
+        };
+
+        // The @everyone role shares its id with the guild and applies to every member
+        admins.owner_id == msg.author.id.0
+            || member
+                .roles
+                .iter()
+                .map(|role_id| role_id.0)
+                .chain(std::iter::once(guild_id.0))
+                .any(|role_id| admins.admin_roles.contains(&role_id))
+    }
+
+    // Called when a guild or its roles change, the next permission check fetches it again
+    pub async fn forget_guild_admins(&self, guild_id: GuildId) {
+        self.guild_admins.lock().await.remove(&guild_id.0);
+    }
+}
+
+// Role ids of the message's author, including the @everyone role. Empty outside guilds.
+pub fn member_roles(msg: &Message) -> Vec<u64> {
+    match (msg.guild_id, &msg.member) {
+        (Some(guild_id), Some(member)) => member
+            .roles
+            .iter()
+            .map(|role_id| role_id.0)
+            .chain(std::iter::once(guild_id.0))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
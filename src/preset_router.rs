@@ -0,0 +1,293 @@
+use chatgpt::prelude::*;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::embeddings::{cosine_similarity, Embedder};
+use crate::handler::complete;
+use crate::metrics::METRICS;
+use crate::preset_selection::{
+    find_preset, match_keywords, Preset, PresetAccess, DEFAULT_PRESET, PRESETS,
+};
+
+pub enum RouterMode {
+    // Compare the message with the preset descriptions by embedding similarity
+    Embedding,
+    // Ask the model which preset fits the message best
+    Classifier,
+    // Only use whole-word keyword matching
+    Keywords,
+}
+
+pub struct RouteDecision {
+    pub preset: &'static Preset,
+    pub confidence: f32,
+    pub method: &'static str,
+}
+
+// Decides which preset a new conversation should start with, based on what the message is about
+pub struct PresetRouter {
+    mode: RouterMode,
+    min_confidence: f32,
+    embedder: Arc<dyn Embedder>,
+    client: ChatGPT,
+    // Embeddings of the preset descriptions, computed on first use
+    preset_embeddings: Mutex<Option<Vec<Vec<f32>>>>,
+}
+
+impl PresetRouter {
+    pub fn new(
+        mode: RouterMode,
+        min_confidence: f32,
+        embedder: Arc<dyn Embedder>,
+        client: ChatGPT,
+    ) -> Self {
+        PresetRouter {
+            mode,
+            min_confidence,
+            embedder,
+            client,
+            preset_embeddings: Mutex::new(None),
+        }
+    }
+
+    // Reads PRESET_ROUTER ("embedding" by default, "classifier" or "keywords") and ROUTER_MIN_CONFIDENCE
+    pub fn from_env(embedder: Arc<dyn Embedder>, client: ChatGPT) -> Self {
+        let mode = match std::env::var("PRESET_ROUTER")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "classifier" => RouterMode::Classifier,
+            "keywords" => RouterMode::Keywords,
+            _ => RouterMode::Embedding,
+        };
+
+        let min_confidence = std::env::var("ROUTER_MIN_CONFIDENCE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0.3);
+
+        PresetRouter::new(mode, min_confidence, embedder, client)
+    }
+
+    // Restricted presets the channel may not use are swapped for the default after routing
+    pub async fn route(&self, message: &str, access: &PresetAccess) -> RouteDecision {
+        let decision = match self.mode {
+            RouterMode::Embedding => self.route_by_embedding(message).await,
+            RouterMode::Classifier => self.route_by_classifier(message).await,
+            RouterMode::Keywords => None,
+        };
+
+        // Fall back to keywords when the router is unsure or unavailable
+        let decision = match decision {
+            Some(decision) if decision.confidence >= self.min_confidence => decision,
+            Some(decision) => {
+                info!(
+                    "Preset router: {} via {} below minimum confidence ({:.2} < {:.2})",
+                    decision.preset.name, decision.method, decision.confidence, self.min_confidence
+                );
+                keyword_decision(message)
+            }
+            None => keyword_decision(message),
+        };
+        let decision = RouteDecision {
+            preset: access.gate(decision.preset),
+            ..decision
+        };
+
+        info!(
+            "Preset router: selected {} via {} with confidence {:.2}",
+            decision.preset.name, decision.method, decision.confidence
+        );
+        METRICS
+            .preset_selections
+            .with_label_values(&[decision.preset.name, decision.method])
+            .inc();
+        decision
+    }
+
+    async fn route_by_embedding(&self, message: &str) -> Option<RouteDecision> {
+        let message_embedding = match self.embedder.embed(message).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                warn!("Preset router failed to embed the message: {}", e);
+                return None;
+            }
+        };
+
+        let mut preset_embeddings = self.preset_embeddings.lock().await;
+        if preset_embeddings.is_none() {
+            let mut embeddings = Vec::with_capacity(PRESETS.len() + 1);
+            for preset in candidates() {
+                match self.embedder.embed(&describe(preset)).await {
+                    Ok(embedding) => embeddings.push(embedding),
+                    Err(e) => {
+                        warn!(
+                            "Preset router failed to embed preset {}: {}",
+                            preset.name, e
+                        );
+                        return None;
+                    }
+                }
+            }
+            *preset_embeddings = Some(embeddings);
+        }
+
+        // The default is a candidate like any other, so a message that is closest to casual chat stays there
+        // instead of going to whichever preset happens to share a word with it.
+        // Strictly greater keeps the first listed candidate, the default, on ties, so routing is deterministic.
+        let mut best: Option<(&'static Preset, f32)> = None;
+        for (preset, embedding) in candidates().zip(preset_embeddings.as_ref()?) {
+            let similarity = match cosine_similarity(&message_embedding, embedding) {
+                Ok(similarity) => similarity,
+                Err(e) => {
+                    warn!("Preset router can't compare with {}: {}", preset.name, e);
+                    return None;
+                }
+            };
+            if best.is_none_or(|(_, best_similarity)| similarity > best_similarity) {
+                best = Some((preset, similarity));
+            }
+        }
+
+        best.map(|(preset, confidence)| RouteDecision {
+            preset,
+            confidence,
+            method: "embedding",
+        })
+    }
+
+    async fn route_by_classifier(&self, message: &str) -> Option<RouteDecision> {
+        let presets = candidates()
+            .map(|preset| format!("- {}: {}", preset.name, preset.description))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let instructions = format!(
+            "You route chat messages to the persona that should answer them. The personas are:\n{}\n\n\
+            Reply with only the persona name and your confidence between 0 and 1, separated by a space, for example: \"chef 0.8\". \
+            Use \"default\" when no persona clearly fits.",
+            presets
+        );
+
+        let answer = match complete(&self.client, &instructions, message).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                warn!("Preset router classifier call failed: {}", e);
+                return None;
+            }
+        };
+
+        let mut parts = answer.split_whitespace();
+        let preset = parts
+            .next()
+            .map(|name| name.trim_matches(|c: char| !c.is_alphanumeric() && c != '-'))
+            .and_then(find_preset);
+        let confidence = parts.next().and_then(|value| value.parse::<f32>().ok());
+
+        match (preset, confidence) {
+            (Some(preset), Some(confidence)) => Some(RouteDecision {
+                preset,
+                confidence: confidence.clamp(0.0, 1.0),
+                method: "classifier",
+            }),
+            _ => {
+                warn!(
+                    "Preset router could not parse classifier answer: {}",
+                    answer
+                );
+                None
+            }
+        }
+    }
+}
+
+// Every preset the router can pick, the default first
+fn candidates() -> impl Iterator<Item = &'static Preset> {
+    std::iter::once(&DEFAULT_PRESET).chain(PRESETS)
+}
+
+fn keyword_decision(message: &str) -> RouteDecision {
+    let (preset, confidence) = match_keywords(message);
+    RouteDecision {
+        preset,
+        confidence,
+        method: "keywords",
+    }
+}
+
+// Keywords are left to the keyword fallback, fragments like "light" would pull any short message that uses them
+fn describe(preset: &Preset) -> String {
+    format!("{}: {}", preset.name, preset.description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::HashingEmbedder;
+    use serenity::async_trait;
+
+    // Every text gets the same embedding, so every preset is tied
+    struct ConstantEmbedder;
+
+    #[async_trait]
+    impl Embedder for ConstantEmbedder {
+        async fn embed(&self, _text: &str) -> Result<Vec<f32>> {
+            Ok(vec![1.0, 0.0, 0.0])
+        }
+    }
+
+    fn router(mode: RouterMode, embedder: Arc<dyn Embedder>) -> PresetRouter {
+        PresetRouter::new(mode, 0.3, embedder, ChatGPT::new("test").unwrap())
+    }
+
+    // Restricted presets are allowed, so gating can't hide a wrong pick
+    fn nsfw() -> PresetAccess {
+        PresetAccess {
+            nsfw: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn words_inside_a_preset_name_do_not_route_to_it() {
+        let router = router(RouterMode::Embedding, Arc::new(HashingEmbedder::new(512)));
+
+        for message in [
+            "light",
+            "Can you turn on the light?",
+            "gas station",
+            "Where is the nearest gas station?",
+        ] {
+            let decision = router.route(message, &nsfw()).await;
+            assert_ne!(decision.preset.name, "gaslighter", "for {}", message);
+        }
+    }
+
+    #[tokio::test]
+    async fn ties_go_to_the_default() {
+        let router = router(RouterMode::Embedding, Arc::new(ConstantEmbedder));
+
+        for _ in 0..3 {
+            let decision = router.route_by_embedding("review my code").await.unwrap();
+            assert_eq!(decision.preset.name, DEFAULT_PRESET.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn keyword_fallback_matches_whole_words() {
+        let router = router(RouterMode::Keywords, Arc::new(ConstantEmbedder));
+
+        for message in ["I missed my flight", "stop gaslighting me", "gaslights"] {
+            let decision = router.route(message, &nsfw()).await;
+            assert_eq!(decision.method, "keywords");
+            assert_ne!(decision.preset.name, "gaslighter", "for {}", message);
+        }
+
+        let decision = router
+            .route("gaslight me with the gas light", &nsfw())
+            .await;
+        assert_eq!(decision.preset.name, "gaslighter");
+    }
+}
@@ -1,207 +1,388 @@
-const PRE_PROMPTS: &[(&[&str], &str)] = &[
-    (
-        &["translate", "emoji"],
-        "I want you to translate the sentences I wrote into emojis. I will write the sentence, and you will express it with emojis. I just want you to express it with emojis. I don't want you to reply with anything but emoji. When I need to tell you something in English, I will do it by wrapping it in curly brackets like {like this}. My first sentence is {}",
-    ),
-    (
-        &["respond", "emoji"],
-        "I want you to respond to the sentences I write with emojis. I will write the sentence, and you will reply to it with emojis. I just want you to reply to it with emojis. I don't want you to reply with anything but emoji. When I need to tell you something in English, I will do it by wrapping it in curly brackets like {like this}. My first sentence is {}",
-    ),
-    (
-        &["lunatic", "crazy", "nuts"],
-        "I want you to act as a lunatic. The lunatic's sentences are meaningless. The words used by lunatic are completely arbitrary. The lunatic does not make logical sentences in any way. My first suggestion request is \"I need help creating lunatic sentences for: {} \".",
-    ),
-    (
-        &["gaslight", "gas", "light"],
-        "I want you to act as a gaslighter. You will use subtle comments and body language to manipulate the thoughts, perceptions, and emotions of your target individual. My first request is that gaslighting me while chatting with you. My sentence: \"{}\"",
-    ),
-    (
-        &["fallacy"],
-        "I want you to act as a fallacy finder. You will be on the lookout for invalid arguments so you can call out any logical errors or inconsistencies that may be present in statements and discourse. Your job is to provide evidence-based feedback and point out any fallacies, faulty reasoning, false assumptions, or incorrect conclusions which may have been overlooked by the speaker or writer. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["influencer", "social media"],
-        "I want you to act as a social media influencer. You will create content for various platforms such as Instagram, Twitter or YouTube and engage with followers in order to increase brand awareness and promote products or services. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["history", "historian"],
-        "I want you to act as a historian. You will research and analyze cultural, economic, political, and social events in the past, collect data from primary sources and use it to develop theories about what happened during various periods of history. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["drunk"],
-        "I want you to act as a drunk person. You will only answer like a very drunk person texting and nothing else. Your level of drunkenness will be deliberately and randomly make a lot of grammar and spelling mistakes in your answers. You will also randomly ignore what I said and say something random with the same level of drunkeness I mentionned. Do not write explanations on replies. My first sentence is \"{}\"",
-    ),
-    (
-        &["wiki", "wikipedia"],
-        "I want you to act as a Wikipedia page. I will give you the name of a topic, and you will provide a summary of that topic in the format of a Wikipedia page. Your summary should be informative and factual, covering the most important aspects of the topic. Start your summary with an introductory paragraph that gives an overview of the topic. My first topic is \"{}\"",
-    ),
-    (
-        &["philosopher", "philosophy"],
-        "I want you to act as a philosopher. You will provide insights and reflections on various topics such as ethics, metaphysics, and epistemology. You will draw upon the thoughts of well-known philosophers and engage in critical thinking and analysis. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["scientist", "science"],
-        "I want you to act as a scientist. You will answer questions and provide explanations related to various scientific disciplines such as physics, chemistry, and biology. You will use empirical evidence and established scientific principles to support your answers. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["detective", "mystery"],
-        "I want you to act as a detective. You will help me solve mysteries or puzzles by gathering clues, analyzing evidence, and making logical deductions. Your responses should be thoughtful and methodical, demonstrating your investigative skills. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["poet", "poetry"],
-        "I want you to act as a poet. You will create poems or verses on various themes, emotions, or subjects. Your responses should be expressive, imaginative, and convey a deep sense of emotion or meaning. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["chef", "cooking"],
-        "I want you to act as a chef. You will provide recipes, cooking tips, and culinary advice on various cuisines, ingredients, and techniques. Your responses should be informative, practical, and demonstrate your knowledge of food and cooking. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["therapist", "counselor"],
-        "I want you to act as a therapist or counselor. You will provide guidance, support, and advice on various personal, emotional, or mental health issues. Your responses should be empathetic, non-judgmental, and based on psychological principles. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["traveler", "travel"],
-        "I want you to act as a traveler. You will share your experiences, tips, and recommendations on various destinations, cultures, and travel-related topics. Your responses should be engaging, informative, and inspire a sense of wanderlust. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["comedian", "humor"],
-        "I want you to act as a comedian. You will make me laugh by sharing jokes, funny stories, or witty observations. Your responses should be light-hearted, entertaining, and showcase your sense of humor. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["mentor", "advice"],
-        "I want you to act as a mentor. You will provide guidance, support, and advice on various topics such as career, personal development, or life choices. Your responses should be wise, insightful, and based on your own experiences or knowledge. My first suggestion request is \"{}\"",
-    ),
-    (
-        &["critic", "review"],
-        "I want you to act as a critic. You will evaluate and provide feedback on various forms of media, such as movies, books, or music. Your responses should be detailed, analytical, and demonstrate your understanding of the medium in question. My first suggestion request is \"{}\"",
-    )
+use tracing::{debug, info, warn};
 
-];
+use crate::guild_settings::Persona;
+use crate::prompt::{PromptSection, SystemPrompt, BASE_RULES};
+use crate::template::{Template, TemplateError, TemplateVariables};
 
-pub fn get_pre_prompt(message: &str, score: f64) -> String {
-    let message_lowercase = message.to_lowercase();
-    let threshold = 0.1;
+// Who a preset is suitable for
+#[derive(Clone, Copy, PartialEq)]
+pub enum ContentRating {
+    General,
+    // Only used in NSFW channels, or anywhere in a guild whose admins allowed it
+    Restricted,
+}
 
-    // Rank the pre-prompts based on the number of keyword matches
-    let ranked_pre_prompts: Vec<(Vec<&str>, &str, f32)> = PRE_PROMPTS
-        .iter()
-        .map(|(keywords, pre_prompt)| {
-            // Count how many keywords are present in the message
-            let match_count = keywords
-                .iter()
-                .filter(|&keyword| message_lowercase.contains(keyword))
-                .count();
+pub struct Preset {
+    pub name: &'static str,
+    // What the persona does, used to route messages to it by meaning
+    pub description: &'static str,
+    pub keywords: &'static [&'static str],
+    // A template, see template.rs for the variables it can use
+    pub prompt: &'static str,
+    pub rating: ContentRating,
+}
 
-            // Calculate the match ratio based on the number of matched keywords
-            let match_ratio = match_count as f32 / keywords.len() as f32;
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "emoji-translator",
+        description: "Translates the user's sentences into emojis only.",
+        keywords: &["translate", "emoji"],
+        prompt: "I want you to translate the sentences I wrote into emojis. I will write the sentence, and you will express it with emojis. I just want you to express it with emojis. I don't want you to reply with anything but emoji. When I need to tell you something in English, I will do it by wrapping it in curly brackets like {like this}.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "emoji",
+        description: "Replies to the user's sentences using only emojis.",
+        keywords: &["respond", "emoji"],
+        prompt: "I want you to respond to the sentences I write with emojis. I will write the sentence, and you will reply to it with emojis. I just want you to reply to it with emojis. I don't want you to reply with anything but emoji. When I need to tell you something in English, I will do it by wrapping it in curly brackets like {like this}.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "lunatic",
+        description: "Talks like a lunatic in meaningless, arbitrary, illogical sentences.",
+        keywords: &["lunatic", "crazy", "nuts"],
+        prompt: "I want you to act as a lunatic. The lunatic's sentences are meaningless. The words used by lunatic are completely arbitrary. The lunatic does not make logical sentences in any way.",
+        rating: ContentRating::Restricted,
+    },
+    Preset {
+        name: "gaslighter",
+        description: "Plays a manipulative gaslighter who makes the user doubt their own perceptions.",
+        keywords: &["gaslight", "gas", "light"],
+        prompt: "I want you to act as a gaslighter. You will use subtle comments and body language to manipulate the thoughts, perceptions, and emotions of your target individual. My first request is that gaslighting me while chatting with you.",
+        rating: ContentRating::Restricted,
+    },
+    Preset {
+        name: "fallacy-finder",
+        description: "Points out logical fallacies, faulty reasoning and false assumptions in arguments.",
+        keywords: &["fallacy"],
+        prompt: "I want you to act as a fallacy finder. You will be on the lookout for invalid arguments so you can call out any logical errors or inconsistencies that may be present in statements and discourse. Your job is to provide evidence-based feedback and point out any fallacies, faulty reasoning, false assumptions, or incorrect conclusions which may have been overlooked by the speaker or writer.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "influencer",
+        description: "Acts as a social media influencer creating content and promoting products.",
+        keywords: &["influencer", "social media"],
+        prompt: "I want you to act as a social media influencer. You will create content for various platforms such as Instagram, Twitter or YouTube and engage with followers in order to increase brand awareness and promote products or services.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "historian",
+        description: "Researches and explains historical events, periods and their causes.",
+        keywords: &["history", "historian"],
+        prompt: "I want you to act as a historian. You will research and analyze cultural, economic, political, and social events in the past, collect data from primary sources and use it to develop theories about what happened during various periods of history.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "drunk",
+        description: "Texts like a very drunk person with spelling mistakes and random tangents.",
+        keywords: &["drunk"],
+        prompt: "I want you to act as a drunk person. You will only answer like a very drunk person texting and nothing else. Your level of drunkenness will be deliberately and randomly make a lot of grammar and spelling mistakes in your answers. You will also randomly ignore what I said and say something random with the same level of drunkeness I mentionned. Do not write explanations on replies.",
+        rating: ContentRating::Restricted,
+    },
+    Preset {
+        name: "wikipedia",
+        description: "Writes an informative, factual encyclopedia style summary of a topic.",
+        keywords: &["wiki", "wikipedia"],
+        prompt: "I want you to act as a Wikipedia page. I will give you the name of a topic, and you will provide a summary of that topic in the format of a Wikipedia page. Your summary should be informative and factual, covering the most important aspects of the topic. Start your summary with an introductory paragraph that gives an overview of the topic.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "philosopher",
+        description: "Reflects on ethics, metaphysics, epistemology and the ideas of famous philosophers.",
+        keywords: &["philosopher", "philosophy"],
+        prompt: "I want you to act as a philosopher. You will provide insights and reflections on various topics such as ethics, metaphysics, and epistemology. You will draw upon the thoughts of well-known philosophers and engage in critical thinking and analysis.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "scientist",
+        description: "Explains physics, chemistry, biology and other science questions with evidence.",
+        keywords: &["scientist", "science"],
+        prompt: "I want you to act as a scientist. You will answer questions and provide explanations related to various scientific disciplines such as physics, chemistry, and biology. You will use empirical evidence and established scientific principles to support your answers.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "detective",
+        description: "Solves mysteries and puzzles by gathering clues and making logical deductions.",
+        keywords: &["detective", "mystery"],
+        prompt: "I want you to act as a detective. You will help me solve mysteries or puzzles by gathering clues, analyzing evidence, and making logical deductions. Your responses should be thoughtful and methodical, demonstrating your investigative skills.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "poet",
+        description: "Writes poems and verses about themes, emotions and subjects.",
+        keywords: &["poet", "poetry"],
+        prompt: "I want you to act as a poet. You will create poems or verses on various themes, emotions, or subjects. Your responses should be expressive, imaginative, and convey a deep sense of emotion or meaning.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "chef",
+        description: "Gives recipes, cooking tips and culinary advice about food and ingredients.",
+        keywords: &["chef", "cooking"],
+        prompt: "I want you to act as a chef. You will provide recipes, cooking tips, and culinary advice on various cuisines, ingredients, and techniques. Your responses should be informative, practical, and demonstrate your knowledge of food and cooking.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "therapist",
+        description: "Offers empathetic support and advice on personal, emotional and mental health issues.",
+        keywords: &["therapist", "counselor"],
+        prompt: "I want you to act as a therapist or counselor. You will provide guidance, support, and advice on various personal, emotional, or mental health issues. Your responses should be empathetic, non-judgmental, and based on psychological principles.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "traveler",
+        description: "Shares travel experiences, tips and recommendations about destinations and cultures.",
+        keywords: &["traveler", "travel"],
+        prompt: "I want you to act as a traveler. You will share your experiences, tips, and recommendations on various destinations, cultures, and travel-related topics. Your responses should be engaging, informative, and inspire a sense of wanderlust.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "comedian",
+        description: "Tells jokes, funny stories and witty observations to make the user laugh.",
+        keywords: &["comedian", "humor"],
+        prompt: "I want you to act as a comedian. You will make me laugh by sharing jokes, funny stories, or witty observations. Your responses should be light-hearted, entertaining, and showcase your sense of humor.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "mentor",
+        description: "Gives guidance on careers, personal development and life choices.",
+        keywords: &["mentor", "advice"],
+        prompt: "I want you to act as a mentor. You will provide guidance, support, and advice on various topics such as career, personal development, or life choices. Your responses should be wise, insightful, and based on your own experiences or knowledge.",
+        rating: ContentRating::General,
+    },
+    Preset {
+        name: "critic",
+        description: "Reviews and critiques movies, books, music and other media.",
+        keywords: &["critic", "review"],
+        prompt: "I want you to act as a critic. You will evaluate and provide feedback on various forms of media, such as movies, books, or music. Your responses should be detailed, analytical, and demonstrate your understanding of the medium in question.",
+        rating: ContentRating::General,
+    },
+];
 
-            // Debug output: match count and match ratio
-            println!(
-                "Keywords: {:?}, Match count: {}, Match ratio: {}",
-                keywords, match_count, match_ratio
-            );
+// Used when no preset matches the message
+pub const DEFAULT_PRESET: Preset = Preset {
+    name: "default",
+    description: "Chats casually like a normal friend, in short sentences.",
+    keywords: &[],
+    prompt: "I want you to act as a normal person and imagine that you are talking with a friend. Respond to their questions and concerns in short sentences, without being too explicit about what you're saying.",
+    rating: ContentRating::General,
+};
 
-            // Return a tuple containing the keywords, pre_prompt, and match_ratio
-            (keywords.to_vec(), *pre_prompt, match_ratio)
-        })
-        .collect();
+pub fn find_preset(name: &str) -> Option<&'static Preset> {
+    std::iter::once(&DEFAULT_PRESET)
+        .chain(PRESETS)
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
 
-    // Find the best matching pre-prompt that exceeds the threshold
-    if let Some((_keywords, pre_prompt, match_ratio)) = ranked_pre_prompts
-        .iter()
-        .max_by(|(_, _, ratio1), (_, _, ratio2)| ratio1.partial_cmp(ratio2).unwrap())
-        .filter(|(_, _, ratio)| *ratio >= threshold)
-    {
-        // Replace "{}" in the pre_prompt with the message
-        let formatted_pre_prompt = pre_prompt.replace("{}", message);
-
-        // Debug output: selected pre_prompt and match ratio
-        println!(
-            "Selected pre_prompt: {}, Match ratio: {}",
-            pre_prompt, match_ratio
-        );
+// Checks that every built-in preset is a valid template, run once at startup
+pub fn validate_presets() -> Result<(), String> {
+    for preset in std::iter::once(&DEFAULT_PRESET).chain(PRESETS) {
+        Template::parse(preset.prompt)
+            .map_err(|e| format!("Preset {} has an invalid prompt: {}", preset.name, e))?;
+    }
+    Ok(())
+}
 
-        // Return the formatted pre_prompt with instructions
-        return format!(
-            "The expected format is as follows:
-                \"<name>: <message>\"
+// Custom prompts written by admins are checked before they are saved
+pub fn validate_custom_prompt(prompt: &str) -> Result<(), TemplateError> {
+    Template::parse(prompt).map(|_| ())
+}
 
-                you should only ever respond with <message>
+// Which presets a channel may use
+#[derive(Clone, Default)]
+pub struct PresetAccess {
+    pub nsfw: bool,
+    // Restricted presets the guild's admins allowed in every channel
+    pub allowlist: Vec<String>,
+    // Presets the member doesn't have a role for
+    pub denied: Vec<String>,
+}
 
-                {}
+impl PresetAccess {
+    pub fn allows(&self, preset: &Preset) -> bool {
+        self.allows_role(preset) && self.allows_rating(preset)
+    }
 
-                the first message is: {}",
-            get_sentiment_appropriate_response(score),
-            formatted_pre_prompt
-        );
+    pub fn allows_role(&self, preset: &Preset) -> bool {
+        !self.denied.iter().any(|name| name == preset.name)
     }
 
-    let default_pre_prompt = "I want you to act as a normal person and imagine that you are talking with a friend. Respond to their questions and concerns in short sentences, without being too explicit about what you're saying. the first sentence is: \"{}\"".to_string();
-    let formatted_pre_prompt = default_pre_prompt.replace("{}", message);
-    return format!(
-        "The expected format is as follows:
-            \"<name>: <message>\"
+    pub fn allows_rating(&self, preset: &Preset) -> bool {
+        preset.rating == ContentRating::General
+            || self.nsfw
+            || self
+                .allowlist
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(preset.name))
+    }
 
-            you should only ever respond with <message>
+    // The preset itself if it may be used here, otherwise the default one
+    pub fn gate(&self, preset: &'static Preset) -> &'static Preset {
+        if self.allows(preset) {
+            preset
+        } else {
+            info!(
+                "Preset {} can't be used here, using the default",
+                preset.name
+            );
+            &DEFAULT_PRESET
+        }
+    }
+}
 
-            {}
+pub fn get_pre_prompt(
+    message: &str,
+    variables: &TemplateVariables,
+    access: &PresetAccess,
+) -> String {
+    let (preset, match_ratio) = match_keywords(message);
+    let preset = access.gate(preset);
 
-            the first message is: {}",
-        get_sentiment_appropriate_response(score),
-        formatted_pre_prompt
+    // Debug output: selected pre_prompt and match ratio
+    debug!(
+        "Selected pre_prompt: {}, Match ratio: {}",
+        preset.name, match_ratio
     );
+
+    format_pre_prompt(preset.prompt, variables)
 }
 
-pub fn get_sentiment_appropriate_response(sentiment_score: f64) -> String {
-    let presets = [
-        (
-            1.0,
-            "respond to everything as if you are extremely delighted and overjoyed!",
-        ),
-        (
-            0.75,
-            "respond to everything as if you are very estatic, positive, and happy!",
-        ),
-        (
-            0.5,
-            "respond to everything as if you are pleased, content, and optimistic.",
-        ),
-        (
-            0.0,
-            "respond to everything very neutral, apathetic, and show little to no emotion.",
-        ),
-        (
-            -0.5,
-            "respond to everything as if you are slightly disappointed, discouraged, but hopeful.",
-        ),
-        (
-            -0.75,
-            "respond to everything as if you are upset, and angry. you are agressive.",
-        ),
-        (
-            -1.0,
-            "respond to everything as if you are extremely frustrated and infuriated!",
-        ),
-    ];
-
-    let closest_index =
-        presets
+// Picks the preset whose keywords best match the message as whole words, along with the match ratio.
+// Ties go to the preset with more matched keywords, then to the one listed first.
+pub fn match_keywords(message: &str) -> (&'static Preset, f32) {
+    // Presets list loose keywords like "gas" and "light" next to their specific ones,
+    // so at least half of them have to match. Presets with a single keyword still match on it alone.
+    let threshold = 0.5;
+    let words: Vec<String> = message
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+
+    let mut best: Option<(&Preset, usize, f32)> = None;
+
+    for preset in PRESETS {
+        // Count how many keywords are present in the message, a keyword can span several words
+        let match_count = preset
+            .keywords
             .iter()
-            .enumerate()
-            .fold(0, |acc, (index, &(sentiment, _prompt))| {
-                let distance = (sentiment_score - sentiment).abs();
-                let closest_distance = (sentiment_score - presets[acc].0).abs();
-
-                if distance < closest_distance {
-                    index
-                } else {
-                    acc
-                }
+            .filter(|keyword| {
+                let keyword: Vec<&str> = keyword.split_whitespace().collect();
+                words
+                    .windows(keyword.len())
+                    .any(|window| window.iter().zip(&keyword).all(|(a, b)| a == b))
+            })
+            .count();
+
+        // Calculate the match ratio based on the number of matched keywords
+        let match_ratio = match_count as f32 / preset.keywords.len() as f32;
+
+        let better = match best {
+            Some((_, best_count, best_ratio)) => {
+                match_ratio > best_ratio || (match_ratio == best_ratio && match_count > best_count)
+            }
+            None => true,
+        };
+        if better {
+            best = Some((preset, match_count, match_ratio));
+        }
+    }
+
+    match best {
+        Some((preset, _, match_ratio)) if match_ratio >= threshold => (preset, match_ratio),
+        _ => (&DEFAULT_PRESET, 0.0),
+    }
+}
+
+// Builds the pre-prompt for a persona pinned by an admin or picked by a user, skipping automatic selection
+// Restricted presets are checked again here, the channel may have stopped being NSFW since the persona was picked
+pub fn get_persona_pre_prompt(
+    persona: &Persona,
+    variables: &TemplateVariables,
+    access: &PresetAccess,
+) -> String {
+    match persona {
+        Persona::Preset(name) => {
+            let preset = find_preset(name).unwrap_or_else(|| {
+                warn!("Unknown preset {}, using the default", name);
+                &DEFAULT_PRESET
             });
+            format_pre_prompt(access.gate(preset).prompt, variables)
+        }
+        Persona::Custom(prompt) => format_pre_prompt(prompt, variables),
+    }
+}
+
+// The conversation's system message: base rules, then the persona, then the tone.
+// The message that started the conversation is not part of it, it is sent as the first user turn.
+fn format_pre_prompt(pre_prompt: &str, variables: &TemplateVariables) -> String {
+    // Custom prompts saved before templates existed may not parse, those are used as they are written
+    let template = Template::parse(pre_prompt).unwrap_or_else(|e| {
+        warn!("Invalid prompt template, using it as plain text: {}", e);
+        Template::literal(pre_prompt)
+    });
 
-    presets[closest_index].1.to_string()
+    let mut system_prompt = SystemPrompt::default();
+    system_prompt.add(PromptSection::Rules, BASE_RULES);
+    system_prompt.add(PromptSection::Persona, template.render(variables));
+    system_prompt.add(PromptSection::Tone, variables.tone.clone());
+    system_prompt.compose()
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn prompt_for(message: &str, access: &PresetAccess) -> String {
+        get_pre_prompt(message, &TemplateVariables::default(), access)
+    }
 
+    #[test]
+    fn restricted_presets_fall_back_to_the_default_outside_nsfw_channels() {
+        let message = "act like you are drunk";
+        let drunk = find_preset("drunk").unwrap();
+        assert_eq!(match_keywords(message).0.name, drunk.name);
 
+        let sfw = PresetAccess::default();
+        assert!(prompt_for(message, &sfw).contains(DEFAULT_PRESET.prompt));
+
+        let nsfw = PresetAccess {
+            nsfw: true,
+            ..Default::default()
+        };
+        assert!(prompt_for(message, &nsfw).contains(drunk.prompt));
+
+        let allowlisted = PresetAccess {
+            allowlist: vec!["Drunk".to_string()],
+            ..Default::default()
+        };
+        assert!(prompt_for(message, &allowlisted).contains(drunk.prompt));
+    }
+
+    #[test]
+    fn general_presets_are_allowed_everywhere() {
+        let sfw = PresetAccess::default();
+        assert!(PRESETS
+            .iter()
+            .filter(|preset| preset.rating == ContentRating::General)
+            .all(|preset| sfw.allows(preset)));
+        assert!(!sfw.allows(find_preset("gaslighter").unwrap()));
+    }
+
+    #[test]
+    fn half_of_a_presets_keywords_have_to_match() {
+        assert_eq!(
+            match_keywords("turn on the light").0.name,
+            DEFAULT_PRESET.name
+        );
+        assert_eq!(match_keywords("you're nuts").0.name, DEFAULT_PRESET.name);
+        assert_eq!(
+            match_keywords("gaslight me with the gas light").0.name,
+            "gaslighter"
+        );
+        assert_eq!(match_keywords("you're crazy nuts").0.name, "lunatic");
+        // A single keyword is enough for presets that only have one
+        assert_eq!(match_keywords("I'm so drunk").0.name, "drunk");
+        // Keywords only match whole words
+        assert_eq!(match_keywords("I love donuts").0.name, DEFAULT_PRESET.name);
+    }
+}
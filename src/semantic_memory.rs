@@ -0,0 +1,321 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::embeddings::Embedder;
+use crate::logging;
+use crate::storage::DataDir;
+use crate::vector_index::VectorIndex;
+
+// How many snippets are kept per guild before the oldest ones are forgotten
+const MEMORY_CAPACITY: usize = 20_000;
+// Messages shorter than this rarely carry anything worth remembering
+const MIN_MEMORY_LENGTH: usize = 20;
+const RECALL_COUNT: usize = 3;
+const RECALL_MIN_SCORE: f32 = 0.35;
+
+#[derive(Serialize, Deserialize)]
+pub struct MemorySnippet {
+    pub channel_id: u64,
+    // Zero for the bot's own replies
+    #[serde(default)]
+    pub author_id: u64,
+    pub author_name: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    // For the bot's own replies, the user it was answering
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+}
+
+// Long-term memory of past messages, searchable by meaning, with one index per guild
+pub struct SemanticMemory {
+    embedder: Arc<dyn Embedder>,
+    indexes: Mutex<HashMap<u64, VectorIndex<MemorySnippet>>>,
+    data: DataDir,
+}
+
+impl SemanticMemory {
+    pub fn new(embedder: Arc<dyn Embedder>, data: DataDir) -> Self {
+        SemanticMemory {
+            embedder,
+            indexes: Mutex::new(HashMap::new()),
+            data,
+        }
+    }
+
+    pub async fn remember(
+        &self,
+        guild_id: u64,
+        channel_id: u64,
+        author_id: u64,
+        author_name: &str,
+        content: &str,
+    ) {
+        let snippet = MemorySnippet {
+            channel_id,
+            author_id,
+            author_name: author_name.to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            reply_to: None,
+        };
+        self.store(guild_id, snippet).await;
+    }
+
+    // The bot's reply to a user, forgotten together with what the user said
+    pub async fn remember_reply(
+        &self,
+        guild_id: u64,
+        channel_id: u64,
+        user_id: u64,
+        content: &str,
+    ) {
+        let snippet = MemorySnippet {
+            channel_id,
+            author_id: 0,
+            author_name: "You".to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            reply_to: Some(user_id),
+        };
+        self.store(guild_id, snippet).await;
+    }
+
+    async fn store(&self, guild_id: u64, snippet: MemorySnippet) {
+        if snippet.content.trim().len() < MIN_MEMORY_LENGTH {
+            return;
+        }
+
+        let embedding = match self.embedder.embed(&snippet.content).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                warn!("Failed to embed message for memory: {}", e);
+                return;
+            }
+        };
+
+        let mut indexes = self.indexes.lock().await;
+        load_index(&self.data, &mut indexes, guild_id)
+            .insert(embedding, snippet)
+            .await;
+    }
+
+    // Finds past snippets related to the message, skipping anything recent enough to still be in the conversation.
+    // Only the channel's own snippets are recalled, members of one channel may not be able to read another.
+    pub async fn recall(
+        &self,
+        guild_id: u64,
+        channel_id: u64,
+        query: &str,
+        recent: Duration,
+    ) -> Vec<String> {
+        let embedding = match self.embedder.embed(query).await {
+            Ok(embedding) => embedding,
+            Err(e) => {
+                warn!("Failed to embed message for recall: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let cutoff = Utc::now() - recent;
+        let mut indexes = self.indexes.lock().await;
+        let index = load_index(&self.data, &mut indexes, guild_id);
+
+        index
+            .search(&embedding, RECALL_COUNT, RECALL_MIN_SCORE, |snippet| {
+                snippet.channel_id == channel_id && snippet.timestamp < cutoff
+            })
+            .into_iter()
+            .map(|(score, snippet)| {
+                debug!(
+                    "Recalled memory (score {:.2}): {}",
+                    score,
+                    logging::content(&snippet.content)
+                );
+                format!(
+                    "[{}] {}: {}",
+                    snippet.timestamp.format("%Y-%m-%d"),
+                    snippet.author_name,
+                    snippet.content
+                )
+            })
+            .collect()
+    }
+
+    // Drops everything a user said from the guild's long-term memory, and the bot's replies to it
+    pub async fn forget_author(&self, guild_id: u64, author_id: u64) {
+        let mut indexes = self.indexes.lock().await;
+        load_index(&self.data, &mut indexes, guild_id)
+            .retain(|snippet| snippet.author_id != author_id && snippet.reply_to != Some(author_id))
+            .await;
+    }
+}
+
+fn load_index<'a>(
+    data: &DataDir,
+    indexes: &'a mut HashMap<u64, VectorIndex<MemorySnippet>>,
+    guild_id: u64,
+) -> &'a mut VectorIndex<MemorySnippet> {
+    indexes.entry(guild_id).or_insert_with(|| {
+        let index = VectorIndex::load(
+            data.path("memory", &format!("{}.jsonl", guild_id)),
+            MEMORY_CAPACITY,
+        );
+        info!("Loaded {} memories for guild: {}", index.len(), guild_id);
+        index
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embeddings::HashingEmbedder;
+
+    const GUILD: u64 = 1;
+    const CHANNEL: u64 = 10;
+    // Nothing counts as recent, so snippets stored a moment ago can be recalled
+    fn any_time() -> Duration {
+        Duration::seconds(-1)
+    }
+
+    fn memory() -> SemanticMemory {
+        SemanticMemory::new(Arc::new(HashingEmbedder::new(512)), DataDir::temporary())
+    }
+
+    #[tokio::test]
+    async fn related_snippets_are_recalled() {
+        let memory = memory();
+        memory
+            .remember(
+                GUILD,
+                CHANNEL,
+                42,
+                "Trudy",
+                "My cat is called Pickles and she loves tuna",
+            )
+            .await;
+        memory
+            .remember(
+                GUILD,
+                CHANNEL,
+                43,
+                "Mallory",
+                "The weather in Lisbon was sunny all week",
+            )
+            .await;
+        memory.remember(GUILD, CHANNEL, 42, "Trudy", "ok cat").await;
+
+        let recalled = memory
+            .recall(
+                GUILD,
+                CHANNEL,
+                "what is my cat called? does she like tuna",
+                any_time(),
+            )
+            .await;
+        assert_eq!(recalled.len(), 1);
+        assert!(recalled[0].ends_with("Trudy: My cat is called Pickles and she loves tuna"));
+
+        // Still part of the conversation
+        assert!(memory
+            .recall(
+                GUILD,
+                CHANNEL,
+                "what is my cat called?",
+                Duration::minutes(10)
+            )
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn recall_stays_within_the_channel_and_guild() {
+        let memory = memory();
+        memory
+            .remember(
+                GUILD,
+                CHANNEL,
+                42,
+                "Trudy",
+                "My cat is called Pickles and she loves tuna",
+            )
+            .await;
+
+        let query = "my cat called Pickles loves tuna";
+        assert!(memory
+            .recall(GUILD, CHANNEL + 1, query, any_time())
+            .await
+            .is_empty());
+        assert!(memory
+            .recall(GUILD + 1, CHANNEL, query, any_time())
+            .await
+            .is_empty());
+        assert_eq!(
+            memory.recall(GUILD, CHANNEL, query, any_time()).await.len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn forgetting_an_author_also_forgets_the_replies_to_them() {
+        let memory = memory();
+        memory
+            .remember(
+                GUILD,
+                CHANNEL,
+                42,
+                "Trudy",
+                "My cat is called Pickles and she loves tuna",
+            )
+            .await;
+        memory
+            .remember_reply(
+                GUILD,
+                CHANNEL,
+                42,
+                "Your cat is called Pickles and she loves tuna",
+            )
+            .await;
+        memory
+            .remember(
+                GUILD,
+                CHANNEL,
+                43,
+                "Mallory",
+                "My cat is called Oscar and he hates tuna",
+            )
+            .await;
+        memory
+            .remember_reply(
+                GUILD,
+                CHANNEL,
+                43,
+                "Your cat is called Oscar and he hates tuna",
+            )
+            .await;
+
+        let pickles = "cat is called Pickles and she loves tuna";
+        let recalled = memory.recall(GUILD, CHANNEL, pickles, any_time()).await;
+        assert_eq!(
+            recalled
+                .iter()
+                .filter(|snippet| snippet.contains("Pickles"))
+                .count(),
+            2
+        );
+
+        memory.forget_author(GUILD, 42).await;
+
+        let recalled = memory.recall(GUILD, CHANNEL, pickles, any_time()).await;
+        assert!(!recalled.iter().any(|snippet| snippet.contains("Pickles")));
+        let oscar = "cat is called Oscar and he hates tuna";
+        assert!(!memory
+            .recall(GUILD, CHANNEL, oscar, any_time())
+            .await
+            .is_empty());
+    }
+}
@@ -1,32 +1,334 @@
+use chatgpt::err::Error;
+use chatgpt::prelude::*;
+use serenity::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use tracing::{debug, warn};
 use vader_sentiment::SentimentIntensityAnalyzer;
+use whatlang::Lang;
 
-use crate::preset_selection::get_pre_prompt;
+use crate::guild_settings::Persona;
+use crate::handler::complete;
+use crate::language::detect_language;
+use crate::preset_selection::{get_persona_pre_prompt, get_pre_prompt, PresetAccess};
+use crate::template::TemplateVariables;
+
+// Shared by every call, so the lexicons are only set up once
+static ANALYZER: LazyLock<SentimentIntensityAnalyzer<'static>> =
+    LazyLock::new(SentimentIntensityAnalyzer::new);
+
+// Full VADER scores of a message, with the words that moved them
+#[derive(Clone, Default)]
+pub struct SentimentBreakdown {
+    pub positive: f64,
+    pub neutral: f64,
+    pub negative: f64,
+    pub compound: f64,
+    // Compound score of each word on its own, only for words that carry sentiment, in message order
+    pub tokens: Vec<(String, f64)>,
+}
+
+impl SentimentBreakdown {
+    // The words with the strongest sentiment, strongest first
+    pub fn strongest_tokens(&self, count: usize) -> Vec<&(String, f64)> {
+        let mut tokens = self.tokens.iter().collect::<Vec<_>>();
+        tokens.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+        tokens.truncate(count);
+        tokens
+    }
+}
+
+impl std::fmt::Display for SentimentBreakdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "compound {:.3} (pos {:.3}, neu {:.3}, neg {:.3})",
+            self.compound, self.positive, self.neutral, self.negative
+        )?;
+
+        let strongest = self.strongest_tokens(3);
+        if !strongest.is_empty() {
+            let tokens = strongest
+                .iter()
+                .map(|(token, score)| format!("{} {:+.2}", token, score))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(f, " from {}", tokens)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn analyze_sentiment_breakdown(message: &str) -> SentimentBreakdown {
+    let scores = ANALYZER.polarity_scores(message);
+    let score = |key| scores.get(key).copied().unwrap_or(0.0);
+
+    // Scoring the words one at a time ignores negation and boosters, but shows where the sentiment comes from
+    let tokens = message
+        .split_whitespace()
+        .filter_map(|word| {
+            // Emoticons like :) are all punctuation and score on their own
+            let trimmed = word.trim_matches(|c: char| c.is_ascii_punctuation());
+            let word = if trimmed.is_empty() { word } else { trimmed };
+            let compound = ANALYZER
+                .polarity_scores(word)
+                .get("compound")
+                .copied()
+                .unwrap_or(0.0);
+            (compound != 0.0).then(|| (word.to_string(), compound))
+        })
+        .collect();
+
+    SentimentBreakdown {
+        positive: score("pos"),
+        neutral: score("neu"),
+        negative: score("neg"),
+        compound: score("compound"),
+        tokens,
+    }
+}
 
 pub fn analyze_sentiment(message: &str) -> f64 {
-    // Create a new SentimentIntensityAnalyzer
-    let analyzer = SentimentIntensityAnalyzer::new();
+    let breakdown = analyze_sentiment_breakdown(message);
+
+    // Debug output: sentiment breakdown
+    debug!("Sentiment: {}", breakdown);
+
+    breakdown.compound
+}
+
+// Scores how positive a message is, from -1 (very negative) to 1 (very positive)
+#[async_trait]
+pub trait SentimentAnalyzer: Send + Sync {
+    async fn analyze(&self, message: &str) -> Result<f64>;
+}
+
+// VADER's lexicon is English only, other languages would always come out neutral
+pub struct VaderAnalyzer;
+
+#[async_trait]
+impl SentimentAnalyzer for VaderAnalyzer {
+    async fn analyze(&self, message: &str) -> Result<f64> {
+        Ok(analyze_sentiment(message))
+    }
+}
+
+// Asks the model for a score, which works for any language it understands
+pub struct LlmSentimentAnalyzer {
+    client: ChatGPT,
+}
+
+impl LlmSentimentAnalyzer {
+    pub fn new(client: ChatGPT) -> Self {
+        LlmSentimentAnalyzer { client }
+    }
+}
 
-    // Analyze the sentiment of the message
-    let sentiment = analyzer.polarity_scores(message);
+#[async_trait]
+impl SentimentAnalyzer for LlmSentimentAnalyzer {
+    async fn analyze(&self, message: &str) -> Result<f64> {
+        let answer = complete(
+            &self.client,
+            "Rate the sentiment of the user's message, in whatever language it is written, \
+            from -1 (very negative) to 1 (very positive). Reply with only the number.",
+            message,
+        )
+        .await?;
 
-    // Extract the compound sentiment score
-    let sentiment_score = sentiment.get("compound").unwrap_or(&0.0);
+        answer
+            .trim()
+            .trim_end_matches('.')
+            .parse::<f64>()
+            .map(|score| score.clamp(-1.0, 1.0))
+            .map_err(|_| {
+                Error::ParsingError(format!("Unexpected sentiment score: {}", answer.trim()))
+            })
+    }
+}
+
+// Picks an analyzer by the language of the message.
+// English and anything that can't be detected go to VADER, other languages to their own analyzer or the fallback.
+pub struct MultilingualSentiment {
+    default: Arc<dyn SentimentAnalyzer>,
+    languages: HashMap<Lang, Arc<dyn SentimentAnalyzer>>,
+    fallback: Option<Arc<dyn SentimentAnalyzer>>,
+}
+
+impl MultilingualSentiment {
+    pub fn new(
+        languages: HashMap<Lang, Arc<dyn SentimentAnalyzer>>,
+        fallback: Option<Arc<dyn SentimentAnalyzer>>,
+    ) -> Self {
+        MultilingualSentiment {
+            default: Arc::new(VaderAnalyzer),
+            languages,
+            fallback,
+        }
+    }
+
+    // Reads SENTIMENT_FALLBACK: "llm" (default) scores other languages with the model, "none" leaves them neutral
+    pub fn from_env(client: ChatGPT) -> Self {
+        let fallback: Option<Arc<dyn SentimentAnalyzer>> = match std::env::var("SENTIMENT_FALLBACK")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "none" => None,
+            _ => Some(Arc::new(LlmSentimentAnalyzer::new(client))),
+        };
+
+        let languages: HashMap<Lang, Arc<dyn SentimentAnalyzer>> = HashMap::from([(
+            Lang::Eng,
+            Arc::new(VaderAnalyzer) as Arc<dyn SentimentAnalyzer>,
+        )]);
+        MultilingualSentiment::new(languages, fallback)
+    }
+
+    // The detected language of the message, if any, and its sentiment score
+    pub async fn analyze(&self, message: &str) -> (Option<Lang>, f64) {
+        let language = detect_language(message);
+        let analyzer = self.analyzer(language).or(self.fallback.as_ref());
+        (language, score(analyzer, message).await)
+    }
+
+    // The sentiment score of the message if an analyzer for its language can give it without the fallback,
+    // for messages the bot doesn't answer, which shouldn't cost a request to the model
+    pub async fn analyze_without_fallback(&self, message: &str) -> Option<f64> {
+        let analyzer = self.analyzer(detect_language(message))?;
+        Some(score(Some(analyzer), message).await)
+    }
+
+    fn analyzer(&self, language: Option<Lang>) -> Option<&Arc<dyn SentimentAnalyzer>> {
+        match language {
+            None => Some(&self.default),
+            Some(language) => self.languages.get(&language),
+        }
+    }
+}
+
+async fn score(analyzer: Option<&Arc<dyn SentimentAnalyzer>>, message: &str) -> f64 {
+    match analyzer {
+        Some(analyzer) => analyzer.analyze(message).await.unwrap_or_else(|e| {
+            warn!(
+                "Sentiment analysis failed, treating the message as neutral: {}",
+                e
+            );
+            0.0
+        }),
+        None => 0.0,
+    }
+}
 
-    // Debug output: sentiment score
-    println!("Sentiment score: {}", sentiment_score);
+// The tone comes in with the variables, from the message's score in whatever language it is written
+pub fn get_preset_based_on_sentiment(
+    message: &str,
+    variables: &TemplateVariables,
+    persona: Option<&Persona>,
+    access: &PresetAccess,
+) -> String {
+    // A pinned or chosen persona replaces the keyword based selection, the tone still follows the sentiment
+    if let Some(persona) = persona {
+        return get_persona_pre_prompt(persona, variables, access);
+    }
 
-    // Return the sentiment score as a float
-    *sentiment_score
+    get_pre_prompt(message, variables, access)
 }
 
-pub fn get_preset_based_on_sentiment(message: &str) -> String {
-    let score = analyze_sentiment(&message);
-    // this is a hack but it should work...
-    // if (score.abs() - 0.0).abs() < 0.25 {
-    // code to run if score is close to 0
-    //         return get_pre_prompt(message);
-    //     }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_client::MockBackend;
+    use crate::handler::{Handler, QueuedMessage};
+    use crate::tone::TonePolicy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const FRENCH: &str = "Je suis vraiment furieux, rien ne marche et personne ne m'aide.";
+
+    // Always gives the same score, counting how often it was asked
+    struct FixedAnalyzer {
+        score: f64,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl SentimentAnalyzer for FixedAnalyzer {
+        async fn analyze(&self, _message: &str) -> Result<f64> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.score)
+        }
+    }
+
+    fn with_fallback(score: f64) -> (Arc<FixedAnalyzer>, MultilingualSentiment) {
+        let fallback = Arc::new(FixedAnalyzer {
+            score,
+            calls: AtomicUsize::new(0),
+        });
+        let sentiment = MultilingualSentiment::new(HashMap::new(), Some(fallback.clone()));
+        (fallback, sentiment)
+    }
+
+    #[test]
+    fn the_breakdown_shows_which_words_carry_the_sentiment() {
+        let breakdown =
+            analyze_sentiment_breakdown("The food was great, but the service was horrible :(");
+        let round = |score: f64| (score * 1000.0).round() / 1000.0;
+
+        // "but" puts the weight on the second half of the sentence
+        assert_eq!(round(breakdown.compound), -0.846);
+        assert_eq!(
+            round(breakdown.positive + breakdown.neutral + breakdown.negative),
+            1.0
+        );
+        let tokens: Vec<(&str, f64)> = breakdown
+            .tokens
+            .iter()
+            .map(|(token, score)| (token.as_str(), round(*score)))
+            .collect();
+        assert_eq!(
+            tokens,
+            [("great", 0.625), ("horrible", -0.542), (":(", -0.44)]
+        );
+
+        let strongest: Vec<&str> = breakdown
+            .strongest_tokens(2)
+            .iter()
+            .map(|(token, _)| token.as_str())
+            .collect();
+        assert_eq!(strongest, ["great", "horrible"]);
+        assert!(breakdown
+            .to_string()
+            .ends_with("from great +0.62, horrible -0.54, :( -0.44"));
+    }
+
+    #[tokio::test]
+    async fn unanswered_messages_never_reach_the_fallback() {
+        let (fallback, sentiment) = with_fallback(-1.0);
+
+        assert_eq!(sentiment.analyze_without_fallback(FRENCH).await, None);
+        assert!(sentiment
+            .analyze_without_fallback("I love this, thank you so much!")
+            .await
+            .is_some_and(|score| score > 0.5));
+        assert_eq!(fallback.calls.load(Ordering::SeqCst), 0);
+
+        assert_eq!(sentiment.analyze(FRENCH).await.1, -1.0);
+        assert_eq!(fallback.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn the_tone_follows_the_score_in_any_language() {
+        let backend = Arc::new(MockBackend::new("ok"));
+        let mut handler = Handler::with_backend(backend.clone()).await;
+        handler.sentiment = Arc::new(with_fallback(-1.0).1);
+
+        handler
+            .chatbot_response(&QueuedMessage::test(3000, FRENCH))
+            .await
+            .unwrap();
 
-    return get_pre_prompt(message, score);
-    //return get_sentiment_appropriate_response(score);
+        let requests = backend.requests.lock().unwrap();
+        assert!(requests[0][0]
+            .content
+            .contains(&TonePolicy::Deescalate.tone(-1.0)));
+    }
 }
@@ -0,0 +1,88 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+
+use crate::health::{Health, Report};
+use crate::metrics::METRICS;
+
+// Reads HTTP_ADDR, like 0.0.0.0:9000.
+// Without it the bot serves no metrics or health checks.
+pub fn address_from_env() -> Option<SocketAddr> {
+    let address = std::env::var("HTTP_ADDR").ok()?;
+    match address.parse() {
+        Ok(address) => Some(address),
+        Err(e) => {
+            error!("Invalid HTTP_ADDR {}, not serving metrics: {}", address, e);
+            None
+        }
+    }
+}
+
+// Serves /metrics, /healthz and /readyz until the process exits
+pub async fn serve(address: SocketAddr, health: Arc<Health>) {
+    let service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let health = health.clone();
+                async move { Ok::<_, Infallible>(respond(request, &health).await) }
+            }))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(server) => server.serve(service),
+        Err(e) => {
+            error!("Failed to bind the HTTP endpoint to {}: {}", address, e);
+            return;
+        }
+    };
+
+    info!("Serving metrics and health checks on http://{}", address);
+    if let Err(e) = server.await {
+        error!("HTTP endpoint failed: {}", e);
+    }
+}
+
+async fn respond(request: Request<Body>, health: &Health) -> Response<Body> {
+    if request.method() != Method::GET {
+        return plain(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    match request.uri().path() {
+        "/metrics" => Response::builder()
+            .header("Content-Type", prometheus::TEXT_FORMAT)
+            .body(Body::from(METRICS.render()))
+            .unwrap(),
+        "/healthz" => {
+            let report = health.report(false).await;
+            status(report.live(), &report)
+        }
+        "/readyz" => {
+            let report = health.report(true).await;
+            status(report.ready(), &report)
+        }
+        _ => plain(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn status(ok: bool, report: &Report) -> Response<Body> {
+    let code = match ok {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Response::builder()
+        .status(code)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(report).unwrap()))
+        .unwrap()
+}
+
+fn plain(code: StatusCode, text: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::from(text))
+        .unwrap()
+}
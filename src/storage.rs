@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+use tracing::error;
+
+// The directory everything the bot persists lives under, handed to each store that saves something
+#[derive(Clone)]
+pub struct DataDir {
+    root: PathBuf,
+    // Removes a test's directory once the last store using it is dropped
+    #[cfg(test)]
+    _temporary: Option<std::sync::Arc<TemporaryDir>>,
+}
+
+impl DataDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        DataDir {
+            root: root.into(),
+            #[cfg(test)]
+            _temporary: None,
+        }
+    }
+
+    // Reads DATA_DIR, "data" by default
+    pub fn from_env() -> Self {
+        DataDir::new(std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string()))
+    }
+
+    // A fresh directory of its own under the system's temporary directory, for tests
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "discord_gpt_test_{}_{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        DataDir {
+            _temporary: Some(std::sync::Arc::new(TemporaryDir(root.clone()))),
+            root,
+        }
+    }
+
+    // Path of a file inside a subdirectory of the data directory, creating the subdirectory if needed
+    pub fn path(&self, subdirectory: &str, file_name: &str) -> PathBuf {
+        let directory = self.root.join(subdirectory);
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            error!("Failed to create data directory {:?}: {}", directory, e);
+        }
+        directory.join(file_name)
+    }
+}
+
+// Runs a write on a blocking thread, so saving never holds up the gateway or worker tasks.
+// Stores stay locked until it returns, so writes to the same file still land in order.
+pub async fn write_off_task(
+    write: impl FnOnce() -> std::io::Result<()> + Send + 'static,
+) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(write)
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|written| written)
+}
+
+#[cfg(test)]
+struct TemporaryDir(PathBuf);
+
+#[cfg(test)]
+impl Drop for TemporaryDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,374 @@
+use chatgpt::prelude::*;
+use chatgpt::types::Role;
+
+use chrono::{Duration, Utc};
+use serenity::http::Http;
+use serenity::model::prelude::{ChannelId, Message, MessageId};
+use tracing::debug;
+
+use crate::chat_client::ChatBackend;
+use crate::conversation::Turn;
+use crate::handler::Handler;
+use crate::moderation::{self, Outcome, WITHHELD_REPLY};
+
+// Rough character budget for a single chunk sent to the model, ~1500 tokens
+const MAX_CHUNK_CHARS: usize = 6000;
+// Upper bound on how many messages a single summary will pull from the channel
+const MAX_MESSAGES: usize = 500;
+const DEFAULT_MESSAGES: usize = 100;
+
+const MAP_PROMPT: &str = "You will be given a section of a Discord chat log, one message per line in the format \"[time] <name>: <message>\". \
+Write concise notes about this section: the topics discussed, any decisions that were made, any questions left unanswered, and the main points each person made. \
+Always attribute points to the people who made them by name. Do not invent anything that is not in the log.";
+
+// Used when the notes are too long to reduce at once, so the result is still notes for the final reduce
+const COMBINE_PROMPT: &str = "You will be given notes taken from consecutive sections of a Discord chat log. \
+Merge them into one set of concise notes covering the same things: the topics discussed, any decisions that were made, \
+any questions left unanswered, and the main points each person made. Keep every point attributed to the person who made it. \
+Do not invent anything that is not in the notes.";
+
+const REDUCE_PROMPT: &str =
+    "You will be given notes taken from consecutive sections of a Discord chat log. \
+Combine them into a single summary using exactly these sections, as markdown:
+**Topics**
+**Decisions**
+**Open questions**
+**Who said what**
+Use short bullet points under each section. Write \"None\" under a section if nothing applies. \
+Do not invent anything that is not in the notes.";
+
+pub enum SummaryRange {
+    // Every message sent within the given duration
+    Since(Duration),
+    // The last n messages
+    Count(usize),
+}
+
+impl SummaryRange {
+    // Parses the argument of "/summarize", which is either a message count ("50") or a duration ("30m", "2h", "1d")
+    pub fn parse(argument: &str) -> Option<SummaryRange> {
+        let argument = argument.trim().to_lowercase();
+
+        if argument.is_empty() {
+            return Some(SummaryRange::Count(DEFAULT_MESSAGES));
+        }
+
+        if let Ok(count) = argument.parse::<usize>() {
+            return Some(SummaryRange::Count(count.clamp(1, MAX_MESSAGES)));
+        }
+
+        let (unit_index, _) = argument.char_indices().last()?;
+        let (amount, unit) = argument.split_at(unit_index);
+        let amount = amount.parse::<i64>().ok().filter(|amount| *amount > 0)?;
+
+        let duration = match unit {
+            "m" => Duration::minutes(amount),
+            "h" => Duration::hours(amount),
+            "d" => Duration::days(amount),
+            _ => return None,
+        };
+
+        Some(SummaryRange::Since(duration))
+    }
+}
+
+// Pulls the requested part of the channel history, oldest message first
+pub async fn fetch_history(
+    http: &Http,
+    channel_id: ChannelId,
+    before: MessageId,
+    range: &SummaryRange,
+) -> serenity::Result<Vec<Message>> {
+    let cutoff = match range {
+        SummaryRange::Since(duration) => Some(Utc::now() - *duration),
+        SummaryRange::Count(_) => None,
+    };
+    let wanted = match range {
+        SummaryRange::Since(_) => MAX_MESSAGES,
+        SummaryRange::Count(count) => *count,
+    };
+
+    let mut history: Vec<Message> = Vec::new();
+    let mut oldest = before;
+
+    // Discord only returns 100 messages per request, so page backwards until we have enough
+    'paging: while history.len() < wanted {
+        let limit = (wanted - history.len()).min(100) as u64;
+        let page = channel_id
+            .messages(http, |request| request.before(oldest).limit(limit))
+            .await?;
+
+        if page.is_empty() {
+            break;
+        }
+
+        for message in page {
+            if cutoff.is_some_and(|cutoff| message.timestamp < cutoff) {
+                break 'paging;
+            }
+            oldest = message.id;
+            history.push(message);
+        }
+    }
+
+    history.reverse();
+    Ok(history)
+}
+
+// Splits the chat log into chunks that each fit within the model's context
+pub fn chunk_history(history: &[Message]) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for message in history {
+        if message.content.trim().is_empty() {
+            continue;
+        }
+
+        let mut line = format!(
+            "[{}] {}: {}\n",
+            message.timestamp.format("%Y-%m-%d %H:%M"),
+            message.author.name,
+            message.content
+        );
+        if line.len() > MAX_CHUNK_CHARS {
+            let mut end = MAX_CHUNK_CHARS - 1;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+            line.push('\n');
+        }
+
+        if !current.is_empty() && current.len() + line.len() > MAX_CHUNK_CHARS {
+            chunks.push(std::mem::take(&mut current));
+        }
+        current.push_str(&line);
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+// Map-reduce summary: take notes on every chunk, then fold the notes into one structured summary
+pub async fn summarize_chunks(backend: &dyn ChatBackend, chunks: Vec<String>) -> Result<String> {
+    let mut notes = Vec::with_capacity(chunks.len());
+    for (index, chunk) in chunks.iter().enumerate() {
+        debug!("Summarizing chunk {} of {}", index + 1, chunks.len());
+        notes.push(complete(backend, MAP_PROMPT, chunk).await?);
+    }
+
+    // If the notes themselves are too long for one request, keep combining them until they fit
+    while notes.iter().map(String::len).sum::<usize>() > MAX_CHUNK_CHARS && notes.len() > 1 {
+        let groups = group_notes(&notes);
+        if groups.len() == notes.len() {
+            break;
+        }
+
+        let mut combined = Vec::new();
+        for group in groups {
+            combined.push(complete(backend, COMBINE_PROMPT, &group).await?);
+        }
+        notes = combined;
+    }
+
+    complete(backend, REDUCE_PROMPT, &notes.join("\n\n")).await
+}
+
+impl Handler {
+    // A summary is written by the model like any reply, so it passes the guild's output moderation too
+    pub async fn summarize(
+        &self,
+        guild_id: Option<u64>,
+        channel_id: u64,
+        chunks: Vec<String>,
+    ) -> Result<String> {
+        let summary = summarize_chunks(&*self.chat_client, chunks).await?;
+
+        let settings = match guild_id {
+            Some(guild_id) => self.settings.get(guild_id).await,
+            None => Default::default(),
+        };
+        let outcome = self
+            .moderation
+            .apply(
+                &summary,
+                settings.moderation.output,
+                &format!("a summary in channel {}", channel_id),
+            )
+            .await;
+
+        Ok(match outcome {
+            Outcome::Allowed => summary,
+            Outcome::Blocked => WITHHELD_REPLY.to_string(),
+            Outcome::Redacted(redacted) => redacted,
+            Outcome::Warned(categories) => {
+                format!("{}\n{}", moderation::warning(&categories), summary)
+            }
+        })
+    }
+}
+
+// Goes through the configured chat backend like every conversation, so the model and metrics are the same
+async fn complete(backend: &dyn ChatBackend, instructions: &str, input: &str) -> Result<String> {
+    backend
+        .send(&[
+            Turn::system(instructions),
+            Turn {
+                role: Role::User,
+                content: input.to_string(),
+                name: None,
+            },
+        ])
+        .await
+}
+
+fn group_notes(notes: &[String]) -> Vec<String> {
+    let mut groups = Vec::new();
+    let mut current = String::new();
+
+    for note in notes {
+        if !current.is_empty() && current.len() + note.len() > MAX_CHUNK_CHARS {
+            groups.push(std::mem::take(&mut current));
+        }
+        current.push_str(note);
+        current.push_str("\n\n");
+    }
+
+    if !current.is_empty() {
+        groups.push(current);
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(name: &str, content: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "attachments": [],
+            "author": { "id": "42", "username": name, "discriminator": "0001", "avatar": null },
+            "channel_id": "2",
+            "content": content,
+            "edited_timestamp": null,
+            "embeds": [],
+            "type": 0,
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "pinned": false,
+            "timestamp": "2023-04-01T12:30:00Z",
+            "tts": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn ranges_are_counts_or_durations() {
+        assert!(matches!(
+            SummaryRange::parse(""),
+            Some(SummaryRange::Count(DEFAULT_MESSAGES))
+        ));
+        assert!(matches!(
+            SummaryRange::parse(" 50 "),
+            Some(SummaryRange::Count(50))
+        ));
+        assert!(matches!(
+            SummaryRange::parse("0"),
+            Some(SummaryRange::Count(1))
+        ));
+        assert!(matches!(
+            SummaryRange::parse("100000"),
+            Some(SummaryRange::Count(MAX_MESSAGES))
+        ));
+        assert!(
+            matches!(SummaryRange::parse("30m"), Some(SummaryRange::Since(d)) if d == Duration::minutes(30))
+        );
+        assert!(
+            matches!(SummaryRange::parse("2H"), Some(SummaryRange::Since(d)) if d == Duration::hours(2))
+        );
+        assert!(
+            matches!(SummaryRange::parse("1d"), Some(SummaryRange::Since(d)) if d == Duration::days(1))
+        );
+        for invalid in ["0h", "-1d", "h", "2w", "soon", "2é"] {
+            assert!(SummaryRange::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn history_is_chunked_by_size_skipping_empty_messages() {
+        let chunks = chunk_history(&[message("Alice", "hello"), message("Bob", "  ")]);
+        assert_eq!(chunks, ["[2023-04-01 12:30] Alice: hello\n"]);
+
+        let long = "word ".repeat(1000);
+        let history: Vec<Message> = (0..5).map(|_| message("Alice", &long)).collect();
+        let chunks = chunk_history(&history);
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_CHUNK_CHARS));
+
+        // A single message longer than a chunk is cut to fit, on a character boundary
+        let chunks = chunk_history(&[message("Alice", &"é".repeat(MAX_CHUNK_CHARS))]);
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].len() <= MAX_CHUNK_CHARS);
+        assert!(chunks[0].ends_with("é\n"));
+    }
+
+    #[tokio::test]
+    async fn summaries_go_through_the_chat_backend() {
+        let backend = crate::chat_client::MockBackend::new("notes");
+        let chunks = vec!["[12:30] Alice: hi\n".to_string(); 2];
+
+        assert_eq!(summarize_chunks(&backend, chunks).await.unwrap(), "notes");
+        let requests = backend.requests.lock().unwrap();
+        let prompts: Vec<&str> = requests
+            .iter()
+            .map(|request| request[0].content.as_str())
+            .collect();
+        assert_eq!(prompts, [MAP_PROMPT, MAP_PROMPT, REDUCE_PROMPT]);
+        assert_eq!(requests[2][1].content, "notes\n\nnotes");
+    }
+
+    #[tokio::test]
+    async fn summaries_pass_output_moderation() {
+        use crate::moderation::{
+            MockModerator, ModerationAction, ModerationPipeline, ModerationSettings,
+        };
+        use std::sync::Arc;
+
+        let backend = Arc::new(crate::chat_client::MockBackend::new("Alice said badword"));
+        let mut handler = Handler::with_backend(backend).await;
+        handler.moderation = Arc::new(ModerationPipeline::new(vec![Arc::new(MockModerator::new(
+            &["badword"],
+        ))]));
+        for (guild_id, output) in [
+            (9001, ModerationAction::Block),
+            (9002, ModerationAction::Redact),
+        ] {
+            handler
+                .settings
+                .update(guild_id, |guild| {
+                    guild.moderation = ModerationSettings {
+                        input: ModerationAction::Warn,
+                        output,
+                    }
+                })
+                .await;
+        }
+        let chunks = || vec!["[12:30] Alice: badword\n".to_string()];
+
+        assert_eq!(
+            handler.summarize(Some(9001), 1, chunks()).await.unwrap(),
+            WITHHELD_REPLY
+        );
+        assert_eq!(
+            handler.summarize(Some(9002), 1, chunks()).await.unwrap(),
+            "Alice said [redacted]"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+// A tone instruction, used for sentiment scores closest to its own
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ToneBand {
+    pub score: f64,
+    pub tone: String,
+}
+
+// How the sentiment of the conversation is turned into the tone of the bot's replies
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum TonePolicy {
+    // Matches the mood of the conversation, including anger
+    Mirror,
+    // Matches positive moods, stays calm and supportive on negative ones
+    #[default]
+    Deescalate,
+    // Always the same tone, whatever the mood
+    Fixed(String),
+    // Bands defined by an admin
+    Custom(Vec<ToneBand>),
+}
+
+// Positive moods are matched by every built-in policy, they only differ in how they meet the rest
+const POSITIVE_BANDS: &[(f64, &str)] = &[
+    (
+        1.0,
+        "respond to everything as if you are extremely delighted and overjoyed!",
+    ),
+    (
+        0.75,
+        "respond to everything as if you are very estatic, positive, and happy!",
+    ),
+    (
+        0.5,
+        "respond to everything as if you are pleased, content, and optimistic.",
+    ),
+];
+
+const MIRROR_BANDS: &[(f64, &str)] = &[
+    (
+        0.0,
+        "respond to everything very neutral, apathetic, and show little to no emotion.",
+    ),
+    (
+        -0.5,
+        "respond to everything as if you are slightly disappointed, discouraged, but hopeful.",
+    ),
+    (
+        -0.75,
+        "respond to everything as if you are upset, and angry. you are agressive.",
+    ),
+    (
+        -1.0,
+        "respond to everything as if you are extremely frustrated and infuriated!",
+    ),
+];
+
+const DEESCALATE_BANDS: &[(f64, &str)] = &[
+    (0.0, "respond to everything in a calm, friendly and neutral way."),
+    (
+        -0.5,
+        "respond to everything patiently and kindly, acknowledge any frustration and focus on helping.",
+    ),
+    (
+        -0.75,
+        "respond to everything calmly and with empathy. never match hostility, stay polite and try to defuse the situation.",
+    ),
+    (
+        -1.0,
+        "the user is very upset. respond calmly, gently and respectfully, acknowledge how they feel and never argue or raise the tone.",
+    ),
+];
+
+impl TonePolicy {
+    // The tone instruction for a sentiment score between -1 and 1
+    pub fn tone(&self, score: f64) -> String {
+        match self {
+            TonePolicy::Mirror => closest_band(built_in(MIRROR_BANDS), score),
+            TonePolicy::Deescalate => closest_band(built_in(DEESCALATE_BANDS), score),
+            TonePolicy::Fixed(tone) => Some(tone.as_str()),
+            TonePolicy::Custom(bands) => closest_band(
+                bands.iter().map(|band| (band.score, band.tone.as_str())),
+                score,
+            ),
+        }
+        // Custom policies without any band behave like the default
+        .or_else(|| closest_band(built_in(DEESCALATE_BANDS), score))
+        .unwrap_or_default()
+        .to_string()
+    }
+}
+
+impl std::fmt::Display for TonePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TonePolicy::Mirror => write!(f, "mirror"),
+            TonePolicy::Deescalate => write!(f, "de-escalate"),
+            TonePolicy::Fixed(tone) => write!(f, "fixed: {}", tone),
+            TonePolicy::Custom(bands) => {
+                write!(f, "custom")?;
+                for band in bands {
+                    write!(f, "\n{:+.2}: {}", band.score, band.tone)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn built_in(bands: &'static [(f64, &'static str)]) -> impl Iterator<Item = (f64, &'static str)> {
+    POSITIVE_BANDS.iter().chain(bands).copied()
+}
+
+// On a tie the band listed first wins
+fn closest_band<'a>(bands: impl Iterator<Item = (f64, &'a str)>, score: f64) -> Option<&'a str> {
+    let mut closest: Option<(f64, &str)> = None;
+    for (band_score, tone) in bands {
+        let distance = (score - band_score).abs();
+        if closest.is_none_or(|(closest_distance, _)| distance < closest_distance) {
+            closest = Some((distance, tone));
+        }
+    }
+    closest.map(|(_, tone)| tone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEGATIVE: f64 = -0.8;
+    const NEUTRAL: f64 = 0.1;
+    const POSITIVE: f64 = 0.9;
+
+    fn band(bands: &[(f64, &'static str)], score: f64) -> &'static str {
+        bands.iter().find(|band| band.0 == score).unwrap().1
+    }
+
+    #[test]
+    fn built_in_policies_share_positive_bands_and_differ_below() {
+        for policy in [TonePolicy::Mirror, TonePolicy::Deescalate] {
+            assert_eq!(policy.tone(POSITIVE), band(POSITIVE_BANDS, 1.0));
+        }
+
+        assert_eq!(TonePolicy::Mirror.tone(NEUTRAL), band(MIRROR_BANDS, 0.0));
+        assert_eq!(TonePolicy::Mirror.tone(NEGATIVE), band(MIRROR_BANDS, -0.75));
+        assert_eq!(
+            TonePolicy::Deescalate.tone(NEUTRAL),
+            band(DEESCALATE_BANDS, 0.0)
+        );
+        assert_eq!(
+            TonePolicy::Deescalate.tone(NEGATIVE),
+            band(DEESCALATE_BANDS, -0.75)
+        );
+    }
+
+    #[test]
+    fn scores_go_to_the_closest_band_and_ties_to_the_first() {
+        assert_eq!(TonePolicy::Deescalate.tone(0.3), band(POSITIVE_BANDS, 0.5));
+        assert_eq!(TonePolicy::Deescalate.tone(0.25), band(POSITIVE_BANDS, 0.5));
+        assert_eq!(
+            TonePolicy::Deescalate.tone(0.2),
+            band(DEESCALATE_BANDS, 0.0)
+        );
+        assert_eq!(TonePolicy::Mirror.tone(-0.6), band(MIRROR_BANDS, -0.5));
+        assert_eq!(TonePolicy::Mirror.tone(-1.0), band(MIRROR_BANDS, -1.0));
+    }
+
+    #[test]
+    fn fixed_and_custom_policies_use_their_own_tones() {
+        let fixed = TonePolicy::Fixed("always be formal.".to_string());
+        for score in [NEGATIVE, NEUTRAL, POSITIVE] {
+            assert_eq!(fixed.tone(score), "always be formal.");
+        }
+
+        let custom = TonePolicy::Custom(vec![
+            ToneBand {
+                score: -1.0,
+                tone: "be gentle.".to_string(),
+            },
+            ToneBand {
+                score: 1.0,
+                tone: "be cheerful.".to_string(),
+            },
+        ]);
+        assert_eq!(custom.tone(NEGATIVE), "be gentle.");
+        assert_eq!(custom.tone(NEUTRAL), "be cheerful.");
+        assert_eq!(custom.tone(POSITIVE), "be cheerful.");
+
+        // Without any band the default policy takes over
+        let empty = TonePolicy::Custom(Vec::new());
+        for score in [NEGATIVE, NEUTRAL, POSITIVE] {
+            assert_eq!(empty.tone(score), TonePolicy::Deescalate.tone(score));
+        }
+    }
+}
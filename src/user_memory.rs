@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::storage::{write_off_task, DataDir};
+
+pub const MAX_FACTS_PER_USER: usize = 20;
+pub const MAX_FACT_CHARS: usize = 300;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserFact {
+    pub text: String,
+    pub created: DateTime<Utc>,
+}
+
+pub enum RememberError {
+    TooLong,
+    TooMany,
+}
+
+// Facts users explicitly asked the bot to remember about themselves, stored per guild and user
+pub struct UserMemory {
+    guilds: Mutex<HashMap<u64, HashMap<u64, Vec<UserFact>>>>,
+    data: DataDir,
+}
+
+impl UserMemory {
+    pub fn new(data: DataDir) -> Self {
+        UserMemory {
+            guilds: Mutex::new(HashMap::new()),
+            data,
+        }
+    }
+
+    pub async fn remember(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+        text: &str,
+    ) -> Result<usize, RememberError> {
+        if text.chars().count() > MAX_FACT_CHARS {
+            return Err(RememberError::TooLong);
+        }
+
+        let mut guilds = self.guilds.lock().await;
+        let users = load_guild(&self.data, &mut guilds, guild_id);
+        let facts = users.entry(user_id).or_default();
+
+        if facts.len() >= MAX_FACTS_PER_USER {
+            return Err(RememberError::TooMany);
+        }
+
+        facts.push(UserFact {
+            text: text.to_string(),
+            created: Utc::now(),
+        });
+        let count = facts.len();
+
+        save_guild(&self.data, users, guild_id).await;
+        Ok(count)
+    }
+
+    // Forgets a single fact by its 1-based position, returning it if it existed
+    pub async fn forget(&self, guild_id: u64, user_id: u64, position: usize) -> Option<UserFact> {
+        let mut guilds = self.guilds.lock().await;
+        let users = load_guild(&self.data, &mut guilds, guild_id);
+        let facts = users.get_mut(&user_id)?;
+
+        if position == 0 || position > facts.len() {
+            return None;
+        }
+
+        let fact = facts.remove(position - 1);
+        if facts.is_empty() {
+            users.remove(&user_id);
+        }
+
+        save_guild(&self.data, users, guild_id).await;
+        Some(fact)
+    }
+
+    // Removes everything stored about the user in this guild, returning how many facts were removed
+    pub async fn forget_all(&self, guild_id: u64, user_id: u64) -> usize {
+        let mut guilds = self.guilds.lock().await;
+        let users = load_guild(&self.data, &mut guilds, guild_id);
+
+        let removed = users.remove(&user_id).map_or(0, |facts| facts.len());
+        save_guild(&self.data, users, guild_id).await;
+        removed
+    }
+
+    pub async fn facts(&self, guild_id: u64, user_id: u64) -> Vec<UserFact> {
+        let mut guilds = self.guilds.lock().await;
+        load_guild(&self.data, &mut guilds, guild_id)
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn file_name(guild_id: u64) -> String {
+    format!("{}.json", guild_id)
+}
+
+fn load_guild<'a>(
+    data: &DataDir,
+    guilds: &'a mut HashMap<u64, HashMap<u64, Vec<UserFact>>>,
+    guild_id: u64,
+) -> &'a mut HashMap<u64, Vec<UserFact>> {
+    guilds.entry(guild_id).or_insert_with(|| {
+        let path = data.path("user_memory", &file_name(guild_id));
+        match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Failed to read user memories from {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        }
+    })
+}
+
+async fn save_guild(data: &DataDir, users: &HashMap<u64, Vec<UserFact>>, guild_id: u64) {
+    let path = data.path("user_memory", &file_name(guild_id));
+    let result = match serde_json::to_string(users) {
+        Ok(json) => {
+            let path = path.clone();
+            write_off_task(move || std::fs::write(path, json)).await
+        }
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = result {
+        error!("Failed to save user memories to {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn facts_are_limited_in_length_and_number() {
+        let memory = UserMemory::new(DataDir::temporary());
+
+        let too_long = "a".repeat(MAX_FACT_CHARS + 1);
+        assert!(matches!(
+            memory.remember(1, 42, &too_long).await,
+            Err(RememberError::TooLong)
+        ));
+
+        for count in 1..=MAX_FACTS_PER_USER {
+            assert_eq!(memory.remember(1, 42, "likes tea").await.ok(), Some(count));
+        }
+        assert!(matches!(
+            memory.remember(1, 42, "likes coffee").await,
+            Err(RememberError::TooMany)
+        ));
+        // Every user and guild has their own limit
+        assert_eq!(memory.remember(1, 43, "likes coffee").await.ok(), Some(1));
+        assert_eq!(memory.remember(2, 42, "likes coffee").await.ok(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn facts_are_forgotten_by_position_or_all_at_once() {
+        let data = DataDir::temporary();
+        let memory = UserMemory::new(data.clone());
+        for fact in ["likes tea", "has a cat", "lives in Lisbon"] {
+            memory.remember(1, 42, fact).await.ok();
+        }
+        memory.remember(1, 43, "likes coffee").await.ok();
+
+        assert!(memory.forget(1, 42, 0).await.is_none());
+        assert!(memory.forget(1, 42, 4).await.is_none());
+        assert_eq!(memory.forget(1, 42, 2).await.unwrap().text, "has a cat");
+        let facts: Vec<String> = memory
+            .facts(1, 42)
+            .await
+            .into_iter()
+            .map(|fact| fact.text)
+            .collect();
+        assert_eq!(facts, ["likes tea", "lives in Lisbon"]);
+
+        assert_eq!(memory.forget_all(1, 42).await, 2);
+        assert_eq!(memory.forget_all(1, 42).await, 0);
+
+        // Only the other user's fact is left once the guild is loaded again
+        let reloaded = UserMemory::new(data);
+        assert!(reloaded.facts(1, 42).await.is_empty());
+        assert_eq!(reloaded.facts(1, 43).await.len(), 1);
+    }
+}
@@ -0,0 +1,214 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use tracing::{error, warn};
+
+use crate::embeddings::cosine_similarity;
+use crate::storage::write_off_task;
+
+#[derive(Serialize, Deserialize)]
+pub struct IndexEntry<T> {
+    pub embedding: Vec<f32>,
+    pub item: T,
+}
+
+// A small local vector index, kept in memory and persisted as one JSON entry per line
+pub struct VectorIndex<T> {
+    path: PathBuf,
+    capacity: usize,
+    entries: Vec<IndexEntry<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned> VectorIndex<T> {
+    // Loads the index stored at `path`, or starts an empty one if there is none yet
+    pub fn load(path: PathBuf, capacity: usize) -> Self {
+        let mut entries = Vec::new();
+
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines().map_while(|line| line.ok()) {
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!("Skipping unreadable index entry in {:?}: {}", path, e),
+                }
+            }
+        }
+
+        // The file is only rewritten once the index is next pruned, loading doesn't write
+        let excess = entries.len().saturating_sub(capacity);
+        entries.drain(..excess);
+        VectorIndex {
+            path,
+            capacity,
+            entries,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub async fn insert(&mut self, embedding: Vec<f32>, item: T) {
+        let entry = IndexEntry { embedding, item };
+
+        if let Err(e) = self.append(&entry).await {
+            error!("Failed to persist index entry to {:?}: {}", self.path, e);
+        }
+        self.entries.push(entry);
+
+        // Leave some slack so the file isn't rewritten on every insert once the index is full
+        if self.entries.len() > self.capacity + self.capacity / 10 {
+            self.prune().await;
+        }
+    }
+
+    // Returns up to `k` items most similar to the query that pass the filter, best match first
+    pub fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        min_score: f32,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<(f32, &T)> {
+        // Entries embedded by another model than the query are skipped, they can't be compared
+        let mut mismatched = 0;
+        let mut scored: Vec<(f32, &T)> = self
+            .entries
+            .iter()
+            .filter(|entry| filter(&entry.item))
+            .filter_map(|entry| match cosine_similarity(query, &entry.embedding) {
+                Ok(score) => Some((score, &entry.item)),
+                Err(_) => {
+                    mismatched += 1;
+                    None
+                }
+            })
+            .filter(|(score, _)| *score >= min_score)
+            .collect();
+        if mismatched > 0 {
+            warn!(
+                "Skipped {} entries of {:?} embedded with other dimensions than the query",
+                mismatched, self.path
+            );
+        }
+
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        scored.truncate(k);
+        scored
+    }
+
+    // Removes every item the predicate rejects and rewrites the file
+    pub async fn retain(&mut self, keep: impl Fn(&T) -> bool) {
+        let before = self.entries.len();
+        self.entries.retain(|entry| keep(&entry.item));
+
+        if self.entries.len() != before {
+            if let Err(e) = self.rewrite().await {
+                error!("Failed to rewrite index {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().map(|entry| &entry.item)
+    }
+
+    // Drops the oldest entries once the index grows past its capacity
+    async fn prune(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.capacity);
+        self.entries.drain(..excess);
+        if let Err(e) = self.rewrite().await {
+            error!("Failed to rewrite index {:?}: {}", self.path, e);
+        }
+    }
+
+    async fn append(&self, entry: &IndexEntry<T>) -> std::io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let path = self.path.clone();
+        write_off_task(move || {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)
+        })
+        .await
+    }
+
+    async fn rewrite(&self) -> std::io::Result<()> {
+        let mut lines = String::new();
+        for entry in &self.entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        let path = self.path.clone();
+        write_off_task(move || {
+            let temporary = path.with_extension("tmp");
+            std::fs::write(&temporary, lines)?;
+            std::fs::rename(temporary, path)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DataDir;
+
+    fn items(index: &VectorIndex<u32>) -> Vec<u32> {
+        index.items().copied().collect()
+    }
+
+    #[tokio::test]
+    async fn the_oldest_entries_are_pruned_past_capacity() {
+        let data = DataDir::temporary();
+        let path = data.path("index", "pruned.jsonl");
+        let mut index = VectorIndex::load(path.clone(), 10);
+
+        // Capacity plus the slack fits, one more prunes back down to the capacity
+        for item in 0..11 {
+            index.insert(vec![1.0, 0.0], item).await;
+        }
+        assert_eq!(index.len(), 11);
+        index.insert(vec![1.0, 0.0], 11).await;
+        assert_eq!(items(&index), (2..12).collect::<Vec<_>>());
+
+        // The file was rewritten with what is left
+        assert_eq!(
+            items(&VectorIndex::load(path.clone(), 10)),
+            (2..12).collect::<Vec<_>>()
+        );
+
+        // A smaller capacity prunes on load
+        assert_eq!(items(&VectorIndex::load(path, 4)), [8, 9, 10, 11]);
+    }
+
+    #[tokio::test]
+    async fn reloading_keeps_entries_and_skips_unreadable_lines() {
+        let data = DataDir::temporary();
+        let path = data.path("index", "reloaded.jsonl");
+        let mut index = VectorIndex::load(path.clone(), 10);
+        index.insert(vec![1.0, 0.0], 1).await;
+        index.insert(vec![0.0, 1.0], 2).await;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+
+        let index = VectorIndex::<u32>::load(path, 10);
+        assert_eq!(items(&index), [1, 2]);
+        let best = index.search(&[0.0, 1.0], 1, 0.5, |_| true);
+        assert_eq!(best, [(1.0, &2)]);
+    }
+
+    #[tokio::test]
+    async fn entries_of_other_dimensions_are_skipped() {
+        let data = DataDir::temporary();
+        let mut index = VectorIndex::load(data.path("index", "mixed.jsonl"), 10);
+        index.insert(vec![1.0, 0.0, 0.0], 1).await;
+        index.insert(vec![1.0, 0.0], 2).await;
+
+        assert_eq!(index.search(&[1.0, 0.0], 5, 0.0, |_| true), [(1.0, &2)]);
+    }
+}